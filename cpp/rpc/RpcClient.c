@@ -24,12 +24,23 @@ HRESULT seh_wrapper_client_DoElevationRequest(
     const HANDLE* pipe_handles,
     const HANDLE* file_handles,
     DWORD sudo_mode,
+    DWORD run_as,
+    DWORD flags,
+    DWORD timeout_ms,
+    DWORD pseudoconsole_size,
     UTF8_STRING application,
     UTF8_STRING args,
     UTF8_STRING target_dir,
     UTF8_STRING env_vars,
+    UTF8_STRING keep_privileges,
+    UTF8_STRING drop_privileges,
+    JOB_LIMITS limits,
+    CONSOLE_STATE console,
+    UTF8_STRING console_title,
     GUID eventId,
-    HANDLE* child)
+    GUID batchId,
+    HANDLE* child,
+    HANDLE* job)
 {
     RpcTryExcept
     {
@@ -39,12 +50,23 @@ HRESULT seh_wrapper_client_DoElevationRequest(
             pipe_handles,
             file_handles,
             sudo_mode,
+            run_as,
+            flags,
+            timeout_ms,
+            pseudoconsole_size,
             application,
             args,
             target_dir,
             env_vars,
+            keep_privileges,
+            drop_privileges,
+            limits,
+            console,
+            console_title,
             eventId,
-            child);
+            batchId,
+            child,
+            job);
     }
     RpcExcept(RpcExceptionFilter(RpcExceptionCode()))
     {
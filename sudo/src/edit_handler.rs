@@ -0,0 +1,239 @@
+use crate::helpers::*;
+use crate::messages::ElevateRequest;
+use crate::run_handler::{prepare_request, ElevatedSession, RunOptions};
+use crate::{r, tracing};
+use std::env;
+use std::fs::File;
+use std::os::windows::io::AsRawHandle;
+use std::path::{Path, PathBuf};
+use windows::{core::*, Win32::Foundation::*};
+
+/// One of the files that we're editing.
+struct EditedFile {
+    /// The real file, that only the elevated side can write to.
+    path: PathBuf,
+    /// The copy of it that we actually hand to the editor.
+    temp_path: PathBuf,
+    /// What the file looked like before the editor got to it.
+    original: Vec<u8>,
+}
+
+/// Figures out what editor to use. This follows the usual convention of
+/// SUDO_EDITOR, then VISUAL, then EDITOR. If none of those are set, we'll use
+/// the editor configured in the registry, and then fall back to notepad.
+///
+/// The editor can include its own args (like `code --wait`), so this returns
+/// the whole commandline, split up into args.
+pub fn find_editor(
+    env_var: impl Fn(&str) -> Option<String>,
+    configured: Option<String>,
+) -> Vec<String> {
+    ["SUDO_EDITOR", "VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(|name| env_var(name))
+        .chain(configured)
+        .map(|editor| split_commandline(&editor))
+        .find(|args| !args.is_empty())
+        .unwrap_or_else(|| vec!["notepad.exe".to_string()])
+}
+
+fn configured_editor() -> Option<String> {
+    windows_registry::CURRENT_USER
+        .open("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Sudo")
+        .and_then(|key| key.get_string("Editor"))
+        .ok()
+}
+
+/// Entrypoint for the (hidden) `sudo read` subcommand. `sudo edit` uses this
+/// from the elevated side, to copy a file it can't read into a file it can.
+///
+/// If the file doesn't exist, this returns ERROR_FILE_NOT_FOUND as the exit
+/// code, so that `sudo edit` can create it.
+pub fn read_file_to_stdout(path: &Path) -> Result<i32> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(ERROR_FILE_NOT_FOUND.0 as i32)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    std::io::copy(&mut file, &mut std::io::stdout().lock())?;
+    Ok(0)
+}
+
+/// Builds a request to run one of our own file subcommands (`read` or
+/// `write`) on `path`, with one of our std handles swapped out for `file`.
+pub fn file_request(
+    subcommand: &str,
+    path: &Path,
+    handle_index: usize,
+    file: &File,
+    batch_id: GUID,
+) -> Result<ElevateRequest> {
+    let args = [
+        env::current_exe()?.to_string_lossy().into_owned(),
+        subcommand.to_string(),
+        "--".to_string(),
+        path.to_string_lossy().into_owned(),
+    ];
+    let mut req = prepare_request(
+        &RunOptions::inline(),
+        &args.iter().collect::<Vec<_>>(),
+        batch_id,
+    )?;
+    req.handles[handle_index] = HANDLE(file.as_raw_handle() as _);
+    // A pseudoconsole would take the place of `file`. If that's only there so
+    // that the target can be recorded, relaying its output does that too.
+    if req.pseudoconsole.take().is_some() {
+        req.relay_output = true;
+    }
+    Ok(req)
+}
+
+/// Entrypoint for `sudo edit`.
+///
+/// We copy each file (from the elevated side) into a temp directory that the
+/// user owns, and then run their editor on the copies, unelevated. Once it
+/// exits, we write back any files that changed (again, from the elevated
+/// side). All of that only takes one elevation.
+pub fn edit_files(files: &[&String]) -> Result<i32> {
+    let paths = files
+        .iter()
+        .map(|file| absolute_path(Path::new(file.as_str())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let temp_dir = env::temp_dir().join(format!("sudo_edit_{}", std::process::id()));
+    // There might be one left over from an old sudo that happened to have the
+    // same PID. That's not something we want to edit.
+    _ = std::fs::remove_dir_all(&temp_dir);
+    std::fs::create_dir(&temp_dir)?;
+
+    let mut keep_temp_dir = false;
+    let result = edit_files_in(&paths, &temp_dir, &mut keep_temp_dir);
+    if !keep_temp_dir {
+        _ = std::fs::remove_dir_all(&temp_dir);
+    }
+    result
+}
+
+fn edit_files_in(paths: &[PathBuf], temp_dir: &Path, keep_temp_dir: &mut bool) -> Result<i32> {
+    let description = join_args(
+        &std::iter::once("edit".to_string())
+            .chain(paths.iter().map(|path| path.to_string_lossy().into_owned()))
+            .collect::<Vec<_>>(),
+    );
+    let session = ElevatedSession::start(&description)?;
+    // Tie all of the requests we make together in the event log.
+    let batch_id = GUID::new()?;
+
+    let mut files = Vec::new();
+    for (index, path) in paths.iter().enumerate() {
+        // Give each copy its own directory, so that it can keep its real name.
+        // That way the editor shows the right name (and picks the right syntax
+        // highlighting), even if two of the files have the same name.
+        let copy_dir = temp_dir.join(index.to_string());
+        std::fs::create_dir(&copy_dir)?;
+        let temp_path = copy_dir.join(path.file_name().unwrap_or(path.as_os_str()));
+
+        let temp_file = File::create(&temp_path)?;
+        let code = session.run(&file_request("read", path, 1, &temp_file, batch_id)?)?;
+        drop(temp_file);
+        // ERROR_FILE_NOT_FOUND just means we're making a new file.
+        if code != 0 && code != ERROR_FILE_NOT_FOUND.0 as i32 {
+            return Ok(code);
+        }
+
+        files.push(EditedFile {
+            path: path.clone(),
+            original: std::fs::read(&temp_path)?,
+            temp_path,
+        });
+    }
+
+    let editor = find_editor(|name| env::var(name).ok(), configured_editor());
+    tracing::trace_log_message(&format!("editor: {editor:?}"));
+    // Resolve the editor ourselves, so that things like `code` (which is
+    // really code.cmd) work too.
+    let editor_path = which::which(&editor[0]).unwrap_or_else(|_| editor[0].clone().into());
+    let status = std::process::Command::new(editor_path)
+        .args(&editor[1..])
+        .args(files.iter().map(|file| &file.temp_path))
+        .status()?;
+    if !status.success() {
+        // Some editors (like vim's `:cq`) use this to mean "never mind".
+        let code = status.code().unwrap_or(-1);
+        let translated_msg = r::IDS_EDIT_EDITORFAILED.get();
+        eprintln!("{}", translated_msg.replace("{0}", &code.to_string()));
+        return Ok(code);
+    }
+
+    let mut exit_code = 0;
+    for file in &files {
+        if std::fs::read(&file.temp_path)? == file.original {
+            let translated_msg = r::IDS_EDIT_UNCHANGED.get();
+            eprintln!(
+                "{}",
+                translated_msg.replace("{0}", &file.path.to_string_lossy())
+            );
+            continue;
+        }
+
+        let temp_file = File::open(&file.temp_path)?;
+        let code = session.run(&file_request("write", &file.path, 0, &temp_file, batch_id)?)?;
+        if code != 0 {
+            // Don't throw away their changes.
+            *keep_temp_dir = true;
+            let translated_msg = r::IDS_EDIT_WRITEFAILED.get();
+            let replaced = translated_msg
+                .replace("{0}", &file.path.to_string_lossy())
+                .replace("{1}", &file.temp_path.to_string_lossy());
+            eprintln!("{}", replaced);
+            if exit_code == 0 {
+                exit_code = code;
+            }
+        }
+    }
+
+    Ok(exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env_from(vars: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn test_find_editor_precedence() {
+        let vars = &[("EDITOR", "vim"), ("VISUAL", "code --wait")];
+        assert_eq!(find_editor(env_from(vars), None), ["code", "--wait"]);
+
+        let vars = &[("EDITOR", "vim"), ("SUDO_EDITOR", "hx")];
+        assert_eq!(find_editor(env_from(vars), Some("edit".into())), ["hx"]);
+
+        let vars = &[("EDITOR", "vim")];
+        assert_eq!(find_editor(env_from(vars), Some("edit".into())), ["vim"]);
+    }
+
+    #[test]
+    fn test_find_editor_fallbacks() {
+        assert_eq!(
+            find_editor(
+                env_from(&[]),
+                Some(r#""C:\Program Files\Foo\foo.exe" -w"#.into())
+            ),
+            [r"C:\Program Files\Foo\foo.exe", "-w"]
+        );
+        assert_eq!(find_editor(env_from(&[]), None), ["notepad.exe"]);
+
+        // A blank variable shouldn't stop us from looking at the next one.
+        let vars = &[("SUDO_EDITOR", "  "), ("EDITOR", "vim")];
+        assert_eq!(find_editor(env_from(vars), None), ["vim"]);
+    }
+}
@@ -1,87 +1,742 @@
 use crate::helpers::*;
-use crate::logging_bindings::event_log_request;
-use crate::messages::ElevateRequest;
+use crate::iolog::{recording_file_name, Header, Recorder, Utf8Chunker};
+use crate::logging_bindings::{
+    event_log_detached, event_log_over_the_shoulder, event_log_privileges, event_log_request,
+};
+use crate::messages::{ApprovedRequest, ConsoleState, ElevateRequest, JobLimits};
 use crate::rpc_bindings_server::rpc_server_setup;
+use crate::tokens::{
+    current_token_for_restricting, impersonate_system, restrict_token, system_token,
+    trusted_installer_token, unelevated_token,
+};
 use crate::tracing;
-use std::ffi::CString;
-use std::os::windows::io::{FromRawHandle, IntoRawHandle};
-use std::os::windows::process::CommandExt;
-use std::process::Stdio;
+use std::ffi::{c_void, CString};
+use std::fs::File;
+use std::os::windows::io::AsRawHandle;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+use windows::Win32::Storage::FileSystem::{GetFileType, ReadFile, WriteFile};
+use windows::Win32::System::Diagnostics::Debug::IMAGE_SUBSYSTEM_WINDOWS_GUI;
+use windows::Win32::System::Pipes::CreatePipe;
+use windows::Win32::System::SystemServices::{JOB_OBJECT_QUERY, JOB_OBJECT_TERMINATE};
+use windows::Win32::UI::WindowsAndMessaging::{ShowWindow, SW_SHOWNORMAL};
 use windows::{
-    core::*, Win32::Foundation::*, Win32::System::Console::*, Win32::System::Threading::*,
+    core::*, Win32::Foundation::*, Win32::Security::*, Win32::System::Console::*,
+    Win32::System::Environment::*, Win32::System::JobObjects::*, Win32::System::Threading::*,
 };
 
-fn handle_to_stdio(h: HANDLE) -> Stdio {
-    if h.is_invalid() {
-        return Stdio::inherit();
+/// Duplicates a handle as inheritable, so that we can hand it to the child.
+fn inheritable_handle(h: HANDLE) -> Result<Owned<HANDLE>> {
+    unsafe {
+        let p = GetCurrentProcess();
+        let mut clone = Owned::default();
+        DuplicateHandle(p, h, p, &mut *clone, 0, true, DUPLICATE_SAME_ACCESS)?;
+        Ok(clone)
+    }
+}
+
+/// Figures out which handle the child should use for one of its std handles.
+/// If the request came with a handle (because the client's was redirected),
+/// we'll use that. Otherwise the child gets ours, which is the console we
+/// attached to.
+fn child_std_handle(request: &ElevateRequest, index: usize) -> Result<Owned<HANDLE>> {
+    // In disabled input mode, set stdin to NUL. We don't want the target
+    // application to be able to read anything from stdin. (Its console is a
+    // hidden one that nobody types into, so it can't go around this by
+    // opening CONIN$ either.)
+    //
+    // An admin can let it have a redirected stdin, though. The client never
+    // sends us its console, but we check that this isn't one anyway.
+    if index == 0 && request.sudo_mode == SudoMode::DisableInput {
+        let h = request.handles[0];
+        let config: RegistryConfigProvider = Default::default();
+        if !h.is_invalid() && is_redirected_input_allowed(&config, unsafe { GetFileType(h) }) {
+            return inheritable_handle(h);
+        }
+        let nul = std::fs::File::open("NUL")?;
+        return inheritable_handle(HANDLE(nul.as_raw_handle() as _));
+    }
+
+    let h = request.handles[index];
+    if !h.is_invalid() {
+        return inheritable_handle(h);
     }
 
+    // A new window gets its own console, and with it, its own std handles. A
+    // detached target doesn't get a console at all, and one in disabled input
+    // mode gets a hidden one of its own, so neither should get handles to
+    // ours. (In disabled input mode, the client relays the target's output
+    // for it.) Neither does a headless one - the client didn't have a console
+    // to give it.
+    if request.sudo_mode != SudoMode::Normal || request.detached || request.headless {
+        return Ok(Owned::default());
+    }
+
+    const STD_HANDLES: [STD_HANDLE; 3] = [STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, STD_ERROR_HANDLE];
+    match unsafe { GetStdHandle(STD_HANDLES[index]) } {
+        Ok(ours) if !ours.is_invalid() => inheritable_handle(ours),
+        _ => Ok(Owned::default()),
+    }
+}
+
+/// Copies the default environment for the given user out of
+/// CreateEnvironmentBlock.
+fn env_block_for_token(token: HANDLE) -> Result<Vec<u16>> {
     unsafe {
-        let p = GetCurrentProcess();
-        let mut clone = Default::default();
-        match DuplicateHandle(p, h, p, &mut clone, 0, true, DUPLICATE_SAME_ACCESS) {
-            Ok(_) => Stdio::from_raw_handle(clone.0 as _),
-            Err(_) => Stdio::null(),
+        let mut env = std::ptr::null_mut();
+        CreateEnvironmentBlock(&mut env, token, false)?;
+        let start = env as *const u16;
+        // The block ends with an empty string, so look for two nulls in a row.
+        let mut len = 0;
+        while *start.add(len) != 0 || *start.add(len + 1) != 0 {
+            len += 1;
         }
+        let block = std::slice::from_raw_parts(start, len + 2).to_vec();
+        _ = DestroyEnvironmentBlock(env);
+        Ok(block)
     }
 }
 
-/// Prepare the target process, spawn it, and hand back the Child process. This will take care of setting up the handles for redirected input/output, and setting the environment variables.
-pub fn spawn_target_for_request(request: &ElevateRequest) -> Result<std::process::Child> {
-    tracing::trace_log_message(&format!("Spawning: {}...", &request.application));
+/// The primary token a target runs with, when it isn't running with ours.
+struct TargetToken {
+    token: Owned<HANDLE>,
+    /// Whether this is just a restricted copy of our own token. Anyone can
+    /// start a process with one of those, so unlike someone else's token, it
+    /// doesn't need SYSTEM's SeAssignPrimaryTokenPrivilege.
+    is_ours: bool,
+}
 
-    let mut command_args = std::process::Command::new(request.application.clone());
+/// Gets the primary token that the target should run with, if it isn't going
+/// to run with ours.
+fn token_for_request(request: &ElevateRequest) -> Result<Option<TargetToken>> {
+    let token = match request.run_as {
+        RunAs::Default => None,
+        RunAs::System => Some(system_token(TokenPrimary)?),
+        RunAs::TrustedInstaller => Some(trusted_installer_token(TokenPrimary)?),
+        // If we aren't elevated, we're already the user they want.
+        RunAs::Unelevated if is_running_elevated()? => Some(unelevated_token()?),
+        RunAs::Unelevated => None,
+    };
+    let is_ours = token.is_none();
 
-    command_args.current_dir(request.target_dir.clone());
-    command_args.args(request.args.clone());
+    if request.keep_privileges.is_empty() && request.drop_privileges.is_empty() {
+        return Ok(token.map(|token| TargetToken { token, is_ours }));
+    }
 
-    tracing::trace_log_message(&format!("args: {:?}", &request.args));
+    let token = match token {
+        Some(token) => token,
+        None => current_token_for_restricting()?,
+    };
+    let (restricted, privileges) =
+        restrict_token(*token, &request.keep_privileges, &request.drop_privileges)?;
+    tracing::trace_log_message(&format!("restricted privileges: {privileges:?}"));
+    event_log_privileges(request, &privileges);
+    Ok(Some(TargetToken {
+        token: restricted,
+        is_ours,
+    }))
+}
+
+/// A target process that we started.
+pub struct Target {
+    pub process: Owned<HANDLE>,
+    /// The job the target is in, if it's in one. If the target should be
+    /// killed when sudo exits, closing this kills the target (and anything it
+    /// started), so hang on to it for as long as the target should live. Once
+    /// the target has exited, keep_job_running lets the rest live on.
+    pub job: Option<Owned<HANDLE>>,
+}
+
+/// Makes the job for the target. If `kill_on_close` is set, everything in it
+/// gets killed once the last handle to it closes. Otherwise, the job (and its
+/// limits) sticks around for as long as something is running in it.
+fn create_job(kill_on_close: bool, limits: &JobLimits) -> Result<Owned<HANDLE>> {
+    unsafe {
+        let job = Owned::new(CreateJobObjectW(None, None)?);
+
+        let mut info = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        let basic = &mut info.BasicLimitInformation;
+        if kill_on_close {
+            basic.LimitFlags |= JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        }
+        if limits.memory_limit != 0 {
+            basic.LimitFlags |= JOB_OBJECT_LIMIT_JOB_MEMORY;
+            info.JobMemoryLimit = limits.memory_limit as usize;
+        }
+        if limits.max_processes != 0 {
+            basic.LimitFlags |= JOB_OBJECT_LIMIT_ACTIVE_PROCESS;
+            basic.ActiveProcessLimit = limits.max_processes;
+        }
+        if limits.affinity != 0 {
+            basic.LimitFlags |= JOB_OBJECT_LIMIT_AFFINITY;
+            basic.Affinity = limits.affinity as usize;
+        }
+        SetInformationJobObject(
+            *job,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const c_void,
+            std::mem::size_of_val(&info) as u32,
+        )?;
+
+        // The CPU rate is a hard cap, so that a busy target can't take the
+        // rest of the machine down with it, even if the CPUs are otherwise
+        // idle.
+        if limits.cpu_rate != 0 {
+            let cpu_info = JOBOBJECT_CPU_RATE_CONTROL_INFORMATION {
+                ControlFlags: JOB_OBJECT_CPU_RATE_CONTROL_ENABLE
+                    | JOB_OBJECT_CPU_RATE_CONTROL_HARD_CAP,
+                Anonymous: JOBOBJECT_CPU_RATE_CONTROL_INFORMATION_0 {
+                    CpuRate: limits.cpu_rate,
+                },
+            };
+            SetInformationJobObject(
+                *job,
+                JobObjectCpuRateControlInformation,
+                &cpu_info as *const _ as *const c_void,
+                std::mem::size_of_val(&cpu_info) as u32,
+            )?;
+        }
+
+        Ok(job)
+    }
+}
+
+/// Turns off JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE for a target's job, once the
+/// target has exited normally. Killing on exit is for when the client goes
+/// away while the target is still running. Anything the target left running
+/// on purpose (like a daemon) gets to keep going, with the job's limits.
+pub fn keep_job_running(job: HANDLE) -> Result<()> {
+    unsafe {
+        let mut info = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        QueryInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            &mut info as *mut _ as *mut c_void,
+            std::mem::size_of_val(&info) as u32,
+            None,
+        )?;
+        info.BasicLimitInformation.LimitFlags &= !JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        SetInformationJobObject(
+            job,
+            JobObjectExtendedLimitInformation,
+            &info as *const _ as *const c_void,
+            std::mem::size_of_val(&info) as u32,
+        )
+    }
+}
+
+/// Swaps our console (normally a hidden one) for a new window, set up like the
+/// client's console was. This is all best effort. If any of it doesn't work,
+/// the target still runs, it just gets the default for that setting.
+pub fn make_console_like_clients(state: &ConsoleState, title: &str) {
+    unsafe {
+        _ = FreeConsole();
+        if AllocConsole().is_err() {
+            return;
+        }
+        // We were started hidden, and a console we make picks that up.
+        _ = ShowWindow(GetConsoleWindow(), SW_SHOWNORMAL);
+        _ = SetConsoleCtrlHandler(Some(ignore_ctrl_c), true);
+
+        if state.input_cp != 0 {
+            _ = SetConsoleCP(state.input_cp);
+        }
+        if state.output_cp != 0 {
+            _ = SetConsoleOutputCP(state.output_cp);
+        }
+
+        if let Ok(console_out) = GetStdHandle(STD_OUTPUT_HANDLE) {
+            if state.virtual_terminal != 0 {
+                let mut mode = CONSOLE_MODE::default();
+                if GetConsoleMode(console_out, &mut mode).is_ok() {
+                    _ = SetConsoleMode(console_out, mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING);
+                }
+            }
+            resize_console(
+                console_out,
+                unpack_coord_from_rpc(state.window_size),
+                unpack_coord_from_rpc(state.buffer_size),
+            );
+        }
+
+        if !title.is_empty() {
+            _ = SetConsoleTitleW(&HSTRING::from(title));
+        }
+    }
+}
 
-    if !request.env_vars.is_empty() {
-        command_args.env_clear();
-        command_args.envs(env_from_raw_bytes(&request.env_vars));
+/// Resizes a console's window and buffer. The window can never be bigger than
+/// the buffer, so the buffer has to grow first, and can only shrink to its
+/// final size once the window fits.
+fn resize_console(console_out: HANDLE, window: Option<COORD>, buffer: Option<COORD>) {
+    unsafe {
+        let mut info = CONSOLE_SCREEN_BUFFER_INFO::default();
+        if GetConsoleScreenBufferInfo(console_out, &mut info).is_err() {
+            return;
+        }
+        if let Some(window) = window {
+            let big_enough = COORD {
+                X: info.dwSize.X.max(window.X),
+                Y: info.dwSize.Y.max(window.Y),
+            };
+            _ = SetConsoleScreenBufferSize(console_out, big_enough);
+            let rect = SMALL_RECT {
+                Left: 0,
+                Top: 0,
+                Right: window.X - 1,
+                Bottom: window.Y - 1,
+            };
+            _ = SetConsoleWindowInfo(console_out, true, &rect);
+        }
+        if let Some(buffer) = buffer {
+            _ = SetConsoleScreenBufferSize(console_out, buffer);
+        }
     }
+}
+
+/// Prepare the target process, spawn it, and hand it back. This will take care of setting up the handles for redirected input/output, setting the environment variables, running as the right user, and putting it in a job (if it should be killed when sudo exits, or has limits).
+pub fn spawn_target_for_request(request: &ElevateRequest) -> Result<Target> {
+    tracing::trace_log_message(&format!("Spawning: {}...", &request.application));
+    tracing::trace_log_message(&format!("args: {:?}", &request.args));
+
+    let token = token_for_request(request)?;
+
+    // If they passed -E, the child gets their environment. Otherwise, if it's
+    // running as someone else, it should get that user's environment, not
+    // ours.
+    let env_block = if !request.env_vars.is_empty() {
+        Some(env_block_from_raw_bytes(&request.env_vars))
+    } else if let Some(token) = &token {
+        Some(env_block_for_token(*token.token)?)
+    } else {
+        None
+    };
 
+    let mut creation_flags = CREATE_UNICODE_ENVIRONMENT;
     // If we're in ForceNewWindow mode, we want the target process to use a new
     // console window instead of inheriting the one from the parent process.
-    if request.sudo_mode == SudoMode::ForceNewWindow {
-        command_args.creation_flags(CREATE_NEW_CONSOLE.0);
+    //
+    // A detached target gets no console at all. It's in its own process group
+    // too, so that Ctrl+C in the caller's console doesn't reach it.
+    //
+    // In disabled input mode, the target gets a console of its own, with no
+    // window. Nobody can type into that, so there's nothing for it to read
+    // their keystrokes from, not even by opening CONIN$. It does mean that
+    // any console apps it starts share that console, rather than each
+    // popping up a window of their own, like they would with no console.
+    //
+    // If the client has no console, the target gets a hidden one for the
+    // same reason. There's no one to see it anyway.
+    //
+    // If the client sent us how its console is set up, we've already made the
+    // new window ourselves (see make_console_like_clients), and the target
+    // just inherits it.
+    if request.detached {
+        creation_flags |= DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP;
+    } else if request.sudo_mode == SudoMode::DisableInput || request.headless {
+        creation_flags |= CREATE_NO_WINDOW;
+    } else if request.sudo_mode == SudoMode::ForceNewWindow && request.console.is_empty() {
+        creation_flags |= CREATE_NEW_CONSOLE;
     }
 
-    // Set the stdin/stdout/stderr of the child process In disabled input
-    // mode, set stdin to null. We don't want the target application to be
-    // able to read anything from stdin.
-    command_args.stdin(if request.sudo_mode != SudoMode::DisableInput {
-        handle_to_stdio(request.handles[0])
+    if request.limits.priority_class != 0 {
+        creation_flags |= PROCESS_CREATION_FLAGS(request.limits.priority_class);
+    }
+    // If it times out, the client will send it a Ctrl+Break before killing
+    // it. That goes to a whole process group, and we don't want it hitting the
+    // client's shell too.
+    if request.timeout.is_some() {
+        creation_flags |= CREATE_NEW_PROCESS_GROUP;
+    }
+
+    // Start it suspended, so that it can't get anything started before it's
+    // in the job. If it's going to get killed on a timeout, it needs a job
+    // too, so that it doesn't leave anything it started behind.
+    let job = if request.kill_on_exit || request.limits.needs_job() || request.timeout.is_some() {
+        creation_flags |= CREATE_SUSPENDED;
+        Some(create_job(request.kill_on_exit, &request.limits)?)
     } else {
-        Stdio::null()
-    });
-    command_args.stdout(handle_to_stdio(request.handles[1]));
-    command_args.stderr(handle_to_stdio(request.handles[2]));
-
-    command_args.spawn().map_err(|err| {
-        match err.kind() {
-            std::io::ErrorKind::NotFound => {
-                // This error code is MSG_DIR_BAD_COMMAND_OR_FILE. That's
-                // what CMD uses to indicate a command not found.
-                E_DIR_BAD_COMMAND_OR_FILE.into()
+        None
+    };
+
+    // With a pseudoconsole, the handles the client sent us are the
+    // pseudoconsole's input and output, not the target's. The target gets
+    // the pseudoconsole's std handles, and doesn't need to inherit anything
+    // of ours.
+    let pseudoconsole = match request.pseudoconsole {
+        Some(size) => unsafe {
+            let pseudoconsole =
+                CreatePseudoConsole(size, request.handles[0], request.handles[1], 0)?;
+            Some(Owned::new(pseudoconsole))
+        },
+        None => None,
+    };
+    let mut attributes = match &pseudoconsole {
+        Some(pseudoconsole) => {
+            creation_flags |= EXTENDED_STARTUPINFO_PRESENT;
+            Some(PseudoconsoleAttribute::new(**pseudoconsole)?)
+        }
+        None => None,
+    };
+    let inherit_handles = pseudoconsole.is_none();
+
+    let std_handles = match pseudoconsole {
+        Some(_) => Default::default(),
+        None => [
+            child_std_handle(request, 0)?,
+            child_std_handle(request, 1)?,
+            child_std_handle(request, 2)?,
+        ],
+    };
+    let mut startup_info = STARTUPINFOEXW {
+        StartupInfo: STARTUPINFOW {
+            cb: match attributes {
+                Some(_) => std::mem::size_of::<STARTUPINFOEXW>(),
+                None => std::mem::size_of::<STARTUPINFOW>(),
+            } as u32,
+            hStdInput: *std_handles[0],
+            hStdOutput: *std_handles[1],
+            hStdError: *std_handles[2],
+            ..Default::default()
+        },
+        lpAttributeList: attributes
+            .as_mut()
+            .map_or(Default::default(), PseudoconsoleAttribute::as_list),
+    };
+    if std_handles.iter().any(|h| !h.is_invalid()) {
+        startup_info.StartupInfo.dwFlags |= STARTF_USESTDHANDLES;
+    }
+
+    let commandline = std::iter::once(&request.application)
+        .chain(&request.args)
+        .collect::<Vec<_>>();
+    let mut commandline: Vec<u16> = join_args(&commandline)
+        .encode_utf16()
+        .chain(std::iter::once(0))
+        .collect();
+    let target_dir = HSTRING::from(request.target_dir.as_str());
+    let target_dir = if target_dir.is_empty() {
+        PCWSTR::null()
+    } else {
+        PCWSTR(target_dir.as_ptr())
+    };
+    let env_ptr = env_block
+        .as_ref()
+        .map(|block| block.as_ptr() as *const c_void);
+
+    let mut process_info = PROCESS_INFORMATION::default();
+    let result = unsafe {
+        match &token {
+            None => CreateProcessW(
+                PCWSTR::null(),
+                PWSTR(commandline.as_mut_ptr()),
+                None,
+                None,
+                inherit_handles,
+                creation_flags,
+                env_ptr,
+                target_dir,
+                &startup_info.StartupInfo,
+                &mut process_info,
+            ),
+            Some(token) => {
+                // Starting a process with someone else's token needs
+                // SeAssignPrimaryTokenPrivilege, which only SYSTEM has. We
+                // might not be able to impersonate SYSTEM (when we aren't
+                // elevated, and they only asked for fewer privileges), but
+                // then we don't need to.
+                let _impersonation = if token.is_ours {
+                    None
+                } else {
+                    Some(impersonate_system()?)
+                };
+                CreateProcessAsUserW(
+                    *token.token,
+                    PCWSTR::null(),
+                    PWSTR(commandline.as_mut_ptr()),
+                    None,
+                    None,
+                    inherit_handles,
+                    creation_flags,
+                    env_ptr,
+                    target_dir,
+                    &startup_info.StartupInfo,
+                    &mut process_info,
+                )
             }
-            _ => err.into(),
         }
-    })
+    };
+
+    if let Err(err) = result {
+        if err.code() == ERROR_FILE_NOT_FOUND.to_hresult()
+            || err.code() == ERROR_PATH_NOT_FOUND.to_hresult()
+        {
+            // This error code is MSG_DIR_BAD_COMMAND_OR_FILE. That's
+            // what CMD uses to indicate a command not found.
+            return Err(E_DIR_BAD_COMMAND_OR_FILE.into());
+        }
+        return Err(err);
+    }
+
+    unsafe {
+        let process = Owned::new(process_info.hProcess);
+        let thread = Owned::new(process_info.hThread);
+        if let Some(job) = &job {
+            if let Err(err) = AssignProcessToJobObject(**job, *process) {
+                _ = TerminateProcess(*process, 1);
+                return Err(err);
+            }
+            ResumeThread(*thread);
+        }
+        if request.detached {
+            event_log_detached(request, process_info.dwProcessId);
+        }
+        if let Some(pseudoconsole) = pseudoconsole {
+            close_pseudoconsole_on_exit(pseudoconsole, *process)?;
+        }
+        Ok(Target { process, job })
+    }
+}
+
+/// A PROC_THREAD_ATTRIBUTE_LIST with the one attribute we ever need: the
+/// pseudoconsole the target gets attached to.
+struct PseudoconsoleAttribute {
+    // usize, rather than u8, so that the list is aligned properly.
+    buffer: Vec<usize>,
+}
+
+impl PseudoconsoleAttribute {
+    fn new(pseudoconsole: HPCON) -> Result<Self> {
+        unsafe {
+            // This first call is just to find out how big the list is, so it
+            // "fails".
+            let mut size = 0;
+            _ = InitializeProcThreadAttributeList(Default::default(), 1, 0, &mut size);
+            let mut buffer = vec![0; size.div_ceil(std::mem::size_of::<usize>())];
+            let list = LPPROC_THREAD_ATTRIBUTE_LIST(buffer.as_mut_ptr() as _);
+            InitializeProcThreadAttributeList(list, 1, 0, &mut size)?;
+            // Once it's initialized, it needs deleting, even if the rest of
+            // this fails.
+            let attribute = PseudoconsoleAttribute { buffer };
+            UpdateProcThreadAttribute(
+                list,
+                0,
+                PROC_THREAD_ATTRIBUTE_PSEUDOCONSOLE as usize,
+                Some(pseudoconsole.0 as *const c_void),
+                std::mem::size_of::<HPCON>(),
+                None,
+                None,
+            )?;
+            Ok(attribute)
+        }
+    }
+
+    fn as_list(&mut self) -> LPPROC_THREAD_ATTRIBUTE_LIST {
+        LPPROC_THREAD_ATTRIBUTE_LIST(self.buffer.as_mut_ptr() as _)
+    }
+}
+
+impl Drop for PseudoconsoleAttribute {
+    fn drop(&mut self) {
+        unsafe { DeleteProcThreadAttributeList(self.as_list()) };
+    }
+}
+
+// The threads that are waiting on something before they can clean up after a
+// target: for clients to exit, so that they can kill the targets they
+// started, or for targets to exit, so that they can close their
+// pseudoconsoles, or finish recording them. We can't exit until these are
+// done.
+static WATCHERS: Mutex<Vec<JoinHandle<()>>> = Mutex::new(Vec::new());
+
+/// Keeps the target's job open for as long as the target and the client are
+/// both around. If the client exits (or gets killed) while the target is
+/// still running, everything in the job gets killed too. If the target exits
+/// first, whatever it left behind keeps running.
+fn watch_client(client: HANDLE, target: HANDLE, job: Owned<HANDLE>) -> Result<()> {
+    let client = duplicate_with_access(client, PROCESS_SYNCHRONIZE.0)?;
+    let target = duplicate_with_access(target, PROCESS_SYNCHRONIZE.0)?;
+    let watcher = std::thread::spawn(move || unsafe {
+        // If they've both exited by now, this says it was the target, and we
+        // leave the job be.
+        let exited = WaitForMultipleObjects(&[*target, *client], false, INFINITE);
+        if exited == WAIT_OBJECT_0 {
+            _ = keep_job_running(*job);
+        } else {
+            tracing::trace_log_message("client exited, cleaning up its target");
+            _ = TerminateJobObject(*job, 1);
+        }
+    });
+    WATCHERS.lock().unwrap().push(watcher);
+    Ok(())
+}
+
+/// Keeps the target's pseudoconsole open until the target exits. Closing it
+/// is what lets the client know that the target's output is all done.
+fn close_pseudoconsole_on_exit(pseudoconsole: Owned<HPCON>, target: HANDLE) -> Result<()> {
+    let target = duplicate_with_access(target, PROCESS_SYNCHRONIZE.0)?;
+    let watcher = std::thread::spawn(move || unsafe {
+        _ = WaitForSingleObject(*target, INFINITE);
+        drop(pseudoconsole);
+    });
+    WATCHERS.lock().unwrap().push(watcher);
+    Ok(())
+}
+
+/// If an admin wants elevated sessions recorded, this sets up the recording
+/// for `request`. The target's output goes to pipes that we record, on its
+/// way to wherever the client asked for it to go. Returns the request to
+/// actually spawn (with those pipes), and our ends of the pipes, which need
+/// closing once the target's started.
+///
+/// We can only see output that comes through the client's handles. A target
+/// that shares the client's console prints straight to it, so that's why the
+/// client asks for a pseudoconsole when recording is on (or relays the
+/// target's output itself, if it can't have one). We don't take its word for
+/// it, though. See is_recordable.
+fn start_recording(
+    request: &ElevateRequest,
+) -> Result<Option<(ElevateRequest, Vec<Owned<HANDLE>>)>> {
+    let config: RegistryConfigProvider = Default::default();
+    if !is_recording_enabled(&config) || request.handles[1..].iter().all(|h| h.is_invalid()) {
+        return Ok(None);
+    }
+
+    let size = request.pseudoconsole.unwrap_or(COORD { X: 80, Y: 24 });
+    let commandline = std::iter::once(&request.application)
+        .chain(&request.args)
+        .collect::<Vec<_>>();
+    let header = Header {
+        width: size.X as u16,
+        height: size.Y as u16,
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs()),
+        command: join_args(&commandline),
+    };
+    let path = create_recordings_dir()?.join(recording_file_name(&request.event_id));
+    let file = File::options().write(true).create_new(true).open(path)?;
+    let recorder = Arc::new(Mutex::new(Recorder::new(file, &header)?));
+    let start = Instant::now();
+
+    let mut recorded = request.clone();
+    let mut ours = Vec::new();
+    for index in [1, 2] {
+        if request.handles[index].is_invalid() {
+            continue;
+        }
+        // The handles the client sent get closed once this request is done,
+        // but we need this one for as long as the target is printing.
+        let to = duplicate_with_same_access(request.handles[index])?;
+        let (read, write) = unsafe {
+            let mut read = Owned::default();
+            let mut write = Owned::default();
+            CreatePipe(&mut *read, &mut *write, None, 0)?;
+            (read, write)
+        };
+        recorded.handles[index] = *write;
+        ours.push(write);
+
+        let recorder = recorder.clone();
+        let watcher = std::thread::spawn(move || record_output(read, to, &recorder, start));
+        WATCHERS.lock().unwrap().push(watcher);
+    }
+    Ok(Some((recorded, ours)))
+}
+
+/// Checks that everything `request`'s target prints would go somewhere that
+/// start_recording can see. A target on the client's console prints straight
+/// to it, and one in a new window prints to that. Any of its handles the
+/// client left on a console aren't recorded either.
+///
+/// A target with a pseudoconsole only has that to print to. In disabled input
+/// mode, or headless, its console is hidden, and the client only sees what
+/// comes through its handles. A detached target has no console at all. A GUI
+/// app in a new window doesn't print anywhere. A target that shares the
+/// client's console can still be recorded if the client sent handles for its
+/// stdout and stderr, since it doesn't send console handles.
+pub fn is_recordable(request: &ElevateRequest) -> bool {
+    if request.pseudoconsole.is_some() || request.detached {
+        return true;
+    }
+    match request.sudo_mode {
+        SudoMode::DisableInput => true,
+        SudoMode::Normal => {
+            request.headless || request.handles[1..].iter().all(|h| !h.is_invalid())
+        }
+        SudoMode::ForceNewWindow => get_exe_subsystem(&request.application)
+            .is_ok_and(|subsystem| subsystem == IMAGE_SUBSYSTEM_WINDOWS_GUI),
+        SudoMode::Disabled => false,
+    }
+}
+
+/// Copies everything the target prints to `to`, and records it too, until the
+/// target's done.
+fn record_output(
+    from: Owned<HANDLE>,
+    to: Owned<HANDLE>,
+    recorder: &Mutex<Recorder<File>>,
+    start: Instant,
+) {
+    let mut chunker = Utf8Chunker::default();
+    let mut buffer = [0u8; 4096];
+    let mut forwarding = true;
+    loop {
+        let mut read = 0;
+        let result = unsafe { ReadFile(*from, Some(&mut buffer), Some(&mut read), None) };
+        if result.is_err() || read == 0 {
+            break;
+        }
+        let data = &buffer[..read as usize];
+        // If the client stops listening, the target still gets recorded.
+        forwarding = forwarding && unsafe { WriteFile(*to, Some(data), None, None) }.is_ok();
+        let text = chunker.push(data);
+        if let Err(err) = recorder.lock().unwrap().output(start.elapsed(), &text) {
+            tracing::trace_log_message(&format!("failed to record output: {err:?}"));
+        }
+    }
+}
+
+/// True if we've still got targets to clean up after. If we exited now,
+/// closing their jobs would kill them early, and closing their pseudoconsoles
+/// would cut them off from their clients.
+pub fn has_watchers() -> bool {
+    WATCHERS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|watcher| !watcher.is_finished())
+}
+
+fn wait_for_watchers() {
+    let watchers = std::mem::take(&mut *WATCHERS.lock().unwrap());
+    for watcher in watchers {
+        _ = watcher.join();
+    }
 }
 
 /// Execute the elevation request.
 /// * Conditionally attach to the parent process's console (if requested)
 /// * Spawn the target process (with redirected input/output if requested, and with the environment variables passed in if needed)
 ///
-/// Called by rust_handle_elevation_request
-pub fn handle_elevation_request(request: &ElevateRequest) -> Result<Owned<HANDLE>> {
+/// Called by rust_handle_elevation_request. `client` is the process that sent
+/// us the request.
+pub fn handle_elevation_request(request: &ElevateRequest, client: HANDLE) -> Result<Target> {
     // Log the request we received to the event log. This should create a pair
     // of events, one for the request, and one for the response, each with the
     // same RequestID.
     event_log_request(false, request);
 
+    // With over-the-shoulder elevation, the user who asked for this isn't the
+    // one we're running as. Make sure the log has both of them.
+    if let Ok(requester) = get_user_for_pid(request.parent_pid) {
+        let elevated = get_current_user()?;
+        if requester != elevated {
+            event_log_over_the_shoulder(request, &requester, &elevated);
+        }
+    }
+
     // Check if the requested sudo mode is allowed
     let config: RegistryConfigProvider = Default::default();
     let allowed_mode = get_allowed_mode(&config)?;
@@ -93,6 +748,46 @@ pub fn handle_elevation_request(request: &ElevateRequest) -> Result<Owned<HANDLE
         return Err(E_ACCESSDENIED.into());
     }
 
+    // A pseudoconsole's input comes from the client, which would give them a
+    // way around disabled input mode.
+    if request.sudo_mode == SudoMode::DisableInput && request.pseudoconsole.is_some() {
+        return Err(E_INVALIDARG.into());
+    }
+
+    // The priority class goes straight into the target's creation flags, so
+    // it had better be one of the ones `--priority` allows, and nothing else.
+    let priority_class = request.limits.priority_class;
+    if priority_class != 0 && !is_allowed_priority_class(priority_class) {
+        return Err(E_INVALIDARG.into());
+    }
+
+    // The client handles dropping elevation itself. There's no reason for
+    // them to be asking us to do it.
+    if request.run_as == RunAs::Unelevated {
+        return Err(E_INVALIDARG.into());
+    }
+
+    // Running as anyone else is off unless an admin turned it on with a
+    // policy.
+    if let Some(policy) = run_as_policy(request.run_as) {
+        if !is_policy_enabled(&config, policy) {
+            tracing::trace_log_message(&format!(
+                "Running as {:?} is disabled by policy",
+                request.run_as
+            ));
+            return Err(ERROR_ACCESS_DISABLED_BY_POLICY.into());
+        }
+    }
+
+    // If an admin wants this recorded, and we can't, the target doesn't get
+    // to run. That includes a target that would print somewhere we can't
+    // see, whatever the client says.
+    if is_recording_enabled(&config) && !is_recordable(request) {
+        tracing::trace_log_message("recording is on, and this target can't be recorded");
+        return Err(E_RECORDING_REQUIRED.into());
+    }
+    let recording = start_recording(request)?;
+
     // If we're in ForceNewWindow mode, we _don't_ want to detach from our
     // current console and reattach to the parent process's console. Instead,
     // we'll just create the target process with CREATE_NEW_CONSOLE.
@@ -102,8 +797,17 @@ pub fn handle_elevation_request(request: &ElevateRequest) -> Result<Owned<HANDLE
     // then we'll have instead just directly ShellExecute'd the target
     // application (and never hit this codepath)
     //
+    // A detached target doesn't get a console either, and one in disabled
+    // input mode gets a hidden one of its own. A target with a pseudoconsole
+    // gets that instead. So there's no point in attaching to theirs in those
+    // cases. In headless mode, they don't have one to attach to.
+    //
     // Almost all the time, we'll actually hit the body of this conditional.
-    if request.sudo_mode != SudoMode::ForceNewWindow {
+    if request.sudo_mode == SudoMode::Normal
+        && !request.detached
+        && !request.headless
+        && request.pseudoconsole.is_none()
+    {
         // It would seem that we always need to detach from the current console,
         // even in redirected i/o mode. In the case that we aren't fully redirected
         // (like, if stdin is redirected but stdout isn't), we'll still need to
@@ -120,50 +824,156 @@ pub fn handle_elevation_request(request: &ElevateRequest) -> Result<Owned<HANDLE
                 // TODO! add some error handling here you goober
             }
         }
+    } else if request.sudo_mode == SudoMode::ForceNewWindow
+        && !request.detached
+        && !request.console.is_empty()
+    {
+        // The target would get a console with the default settings from
+        // CREATE_NEW_CONSOLE. It makes its console while it starts up, so we
+        // can't get at it to fix it from out here. Instead, we make the new
+        // window ourselves, set it up like the client's, and let the target
+        // inherit it.
+        make_console_like_clients(&request.console, &request.console_title);
     }
 
-    // We're attached to the right console, Run the command.
-    let process_launch = spawn_target_for_request(request);
+    // We're attached to the right console, Run the command. If it's being
+    // recorded, it gets the pipes to the recording instead of the handles the
+    // client sent us.
+    let process_launch = match &recording {
+        Some((recorded, _)) => spawn_target_for_request(recorded),
+        None => spawn_target_for_request(request),
+    };
+    // The target has its own copies of the pipes now. Once it's closed them,
+    // the recording is done.
+    drop(recording);
     unsafe {
         _ = SetConsoleCtrlHandler(Some(ignore_ctrl_c), false);
         _ = FreeConsole();
     }
 
-    let child = process_launch?;
+    let Target {
+        process: child,
+        job,
+    } = process_launch?;
+
+    // The client can see what the target's job used (for `--time`). If it has
+    // to enforce a timeout, it also needs to be able to kill the target, and
+    // everything it started. The same goes for stopping a target on Ctrl+C,
+    // when it's in a console of its own that Ctrl+C can't get to. That's all
+    // it gets to do with the job, though.
+    let client_stops_target = request.timeout.is_some()
+        || request.sudo_mode == SudoMode::DisableInput
+        || request.headless;
+    let client_job_access = if client_stops_target {
+        JOB_OBJECT_QUERY | JOB_OBJECT_TERMINATE
+    } else {
+        JOB_OBJECT_QUERY
+    };
+    let client_job = match &job {
+        Some(job) => Some(duplicate_with_access(**job, client_job_access)?),
+        None => None,
+    };
+
+    // If the target should die with the client, keep its job around until the
+    // client exits.
+    if let Some(job) = job.filter(|_| request.kill_on_exit) {
+        watch_client(client, *child, job)?;
+    }
 
     // Limit the things the caller can do with the process handle, because the one we just created is PROCESS_ALL_ACCESS.
     // I tried to use [out, system_handle(sh_process, PROCESS_QUERY_LIMITED_INFORMATION)]
     // in the COM API to have it limit the handle permissions but that didn't work at all.
     // So now we do it manually here.
+    let child_handle = duplicate_with_access(
+        *child,
+        (PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_DUP_HANDLE | PROCESS_SYNCHRONIZE).0,
+    )?;
+    Ok(Target {
+        process: child_handle,
+        job: client_job,
+    })
+}
+
+/// Duplicates one of our handles, with the same access rights.
+fn duplicate_with_same_access(handle: HANDLE) -> Result<Owned<HANDLE>> {
+    unsafe {
+        let mut clone = Owned::default();
+        let current_process = GetCurrentProcess();
+        DuplicateHandle(
+            current_process,
+            handle,
+            current_process,
+            &mut *clone,
+            0,
+            false,
+            DUPLICATE_SAME_ACCESS,
+        )?;
+        Ok(clone)
+    }
+}
+
+/// Duplicates one of our handles, with only the given access rights.
+fn duplicate_with_access(handle: HANDLE, access: u32) -> Result<Owned<HANDLE>> {
     unsafe {
-        let mut child_handle = Owned::default();
+        let mut clone = Owned::default();
         let current_process = GetCurrentProcess();
         DuplicateHandle(
             current_process,
-            HANDLE(child.into_raw_handle() as _),
+            handle,
             current_process,
-            &mut *child_handle,
-            (PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_DUP_HANDLE | PROCESS_SYNCHRONIZE).0,
+            &mut *clone,
+            access,
             false,
-            DUPLICATE_CLOSE_SOURCE,
+            DUPLICATE_HANDLE_OPTIONS(0),
         )?;
-        Ok(child_handle)
+        Ok(clone)
     }
 }
 
 /// Starts the RPC server and blocks until Shutdown() is called.
+///
+/// If `batch` is set, we'll keep handling requests from our client (one at a
+/// time) until it calls Shutdown, rather than stopping after the first one.
+/// Otherwise, we only run the request that was approved: the one with this
+/// `request_hash`, for the commandline in `args`.
 pub fn start_rpc_server(
     parent_pid: u32,
     nonce: u32,
     _caller_sid: Option<&String>,
-    _args: &[&String],
+    args: &[&String],
+    request_hash: Option<&String>,
+    batch: bool,
 ) -> Result<i32> {
     // TODO:48520593 In rust_handle_elevation_request, validate that the parent
     // process handle is the same one that we opened here.
 
+    // A batch gets to run whatever its client sends, for as long as it likes,
+    // so it's never allowed for anyone but the admin who approved it.
+    let approved = match request_hash {
+        _ if batch => None,
+        Some(hash) => Some(ApprovedRequest {
+            hash: hash.clone(),
+            commandline: args.iter().map(|arg| arg.to_string()).collect(),
+        }),
+        // There's nothing we could run without knowing what was approved.
+        None => return Err(E_ACCESSDENIED.into()),
+    };
+
+    // Figure out who the client is now, while it's still waiting for us.
+    let config: RegistryConfigProvider = Default::default();
+    let expected_client = ExpectedClient::from_pid(
+        parent_pid,
+        !batch && is_policy_enabled(&config, POLICY_ALLOW_OVER_THE_SHOULDER),
+        approved,
+    )?;
+
     let endpoint = generate_rpc_endpoint_name(parent_pid, nonce);
     let endpoint = CString::new(endpoint).unwrap();
-    rpc_server_setup(&endpoint, parent_pid)?;
+    rpc_server_setup(&endpoint, expected_client, batch)?;
+
+    // The RPC server is done, but the targets we started might not be. Stick
+    // around so that we can clean up after them.
+    wait_for_watchers();
 
     Ok(0)
 }
@@ -1,20 +1,29 @@
+use crate::messages::ApprovedRequest;
 use crate::rpc_bindings::Utf8Str;
 use crate::trace_log_message;
 use std::ffi::{OsStr, OsString};
 use std::fs::File;
 use std::mem::{size_of, MaybeUninit};
 use std::ops::{Deref, DerefMut};
-use std::os::windows::ffi::OsStringExt;
-use std::os::windows::fs::FileExt;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::fs::{FileExt, OpenOptionsExt};
+use std::os::windows::io::AsRawHandle;
 use std::path::{Path, PathBuf};
 use std::slice::{from_raw_parts, from_raw_parts_mut};
-use windows::Win32::Storage::FileSystem::GetFullPathNameW;
+use std::time::Duration;
+use windows::Win32::Storage::FileSystem::{
+    CreateDirectoryW, FileIdInfo, GetFileInformationByHandleEx, GetFullPathNameW, FILE_ID_INFO,
+    FILE_READ_ATTRIBUTES, FILE_TYPE, FILE_TYPE_DISK, FILE_TYPE_PIPE,
+};
+use windows::Win32::System::Com::CoTaskMemFree;
 use windows::Win32::System::Diagnostics::Debug::{IMAGE_NT_HEADERS32, IMAGE_SUBSYSTEM};
 use windows::Win32::System::Environment::{FreeEnvironmentStringsW, GetEnvironmentStringsW};
 use windows::Win32::System::Rpc::RPC_STATUS;
 use windows::Win32::System::SystemServices::{
-    IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_NT_SIGNATURE, SE_TOKEN_USER, SE_TOKEN_USER_1,
+    ACCESS_ALLOWED_ACE_TYPE, ACCESS_DENIED_ACE_TYPE, IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE,
+    IMAGE_NT_SIGNATURE, SE_TOKEN_USER, SE_TOKEN_USER_1,
 };
+use windows::Win32::UI::Shell::{FOLDERID_ProgramData, SHGetKnownFolderPath, KF_FLAG_DEFAULT};
 use windows::{
     core::*, Win32::Foundation::*, Win32::Security::Authorization::*, Win32::Security::*,
     Win32::System::Console::*, Win32::System::Threading::*,
@@ -28,6 +37,11 @@ pub const E_CANCELLED: HRESULT = ERROR_CANCELLED.to_hresult();
 pub const MSG_DIR_BAD_COMMAND_OR_FILE: WIN32_ERROR = WIN32_ERROR(9009);
 pub const E_DIR_BAD_COMMAND_OR_FILE: HRESULT = MSG_DIR_BAD_COMMAND_OR_FILE.to_hresult();
 pub const E_ACCESS_DISABLED_BY_POLICY: HRESULT = ERROR_ACCESS_DISABLED_BY_POLICY.to_hresult();
+/// `--drop` couldn't find an unelevated token to run the target with.
+pub const E_NO_UNELEVATED_TOKEN: HRESULT = ERROR_NO_TOKEN.to_hresult();
+/// An admin wants elevated sessions recorded, and the target would print
+/// somewhere we can't record.
+pub const E_RECORDING_REQUIRED: HRESULT = ERROR_AUDITING_DISABLED;
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, PartialOrd, Ord)]
 pub enum SudoMode {
@@ -62,6 +76,163 @@ impl From<SudoMode> for i32 {
     }
 }
 
+/// Who the elevated sudo should start the target as.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum RunAs {
+    /// The same (elevated) user that ran sudo.
+    Default = 0,
+    /// NT AUTHORITY\SYSTEM. Requires the POLICY_ALLOW_SYSTEM policy.
+    System = 1,
+    /// NT SERVICE\TrustedInstaller. Requires the
+    /// POLICY_ALLOW_TRUSTED_INSTALLER policy.
+    TrustedInstaller = 2,
+    /// The same user, but unelevated (`sudo --drop`). This one never goes to
+    /// the elevated sudo, we handle it ourselves.
+    Unelevated = 3,
+}
+
+impl TryFrom<u32> for RunAs {
+    type Error = Error;
+    fn try_from(value: u32) -> Result<Self> {
+        match value {
+            0 => Ok(RunAs::Default),
+            1 => Ok(RunAs::System),
+            2 => Ok(RunAs::TrustedInstaller),
+            3 => Ok(RunAs::Unelevated),
+            _ => Err(ERROR_INVALID_PARAMETER.into()),
+        }
+    }
+}
+
+impl From<RunAs> for u32 {
+    fn from(value: RunAs) -> Self {
+        value as u32
+    }
+}
+
+/// The values we accept for `--priority`. These need to line up with
+/// `priority_class_from_name`. REALTIME_PRIORITY_CLASS is deliberately missing:
+/// it can starve the rest of the machine, which is what `--priority` is meant
+/// to prevent.
+pub const PRIORITY_NAMES: [&str; 5] = ["idle", "below-normal", "normal", "above-normal", "high"];
+
+/// Maps a `--priority` name to its priority class.
+pub fn priority_class_from_name(name: &str) -> Option<PROCESS_CREATION_FLAGS> {
+    match name {
+        "idle" => Some(IDLE_PRIORITY_CLASS),
+        "below-normal" => Some(BELOW_NORMAL_PRIORITY_CLASS),
+        "normal" => Some(NORMAL_PRIORITY_CLASS),
+        "above-normal" => Some(ABOVE_NORMAL_PRIORITY_CLASS),
+        "high" => Some(HIGH_PRIORITY_CLASS),
+        _ => None,
+    }
+}
+
+/// True if `class` is exactly one of the priority classes in PRIORITY_NAMES.
+/// The elevated sudo checks this, since it ORs the class into the target's
+/// creation flags, and a client could send it anything.
+pub fn is_allowed_priority_class(class: u32) -> bool {
+    PRIORITY_NAMES
+        .iter()
+        .filter_map(|name| priority_class_from_name(name))
+        .any(|allowed| allowed.0 == class)
+}
+
+/// Parses a `--memory-limit` like "512M" or "2GB" into a number of bytes. The
+/// suffixes are binary (K is 1024), and a plain number is bytes. Zero isn't
+/// allowed, since nothing could run with it.
+pub fn parse_memory_size(value: &str) -> Option<u64> {
+    let value = value.trim().to_ascii_uppercase();
+    let value = value.strip_suffix('B').unwrap_or(&value);
+    let (digits, shift) = match value.char_indices().last()? {
+        (i, 'K') => (&value[..i], 10),
+        (i, 'M') => (&value[..i], 20),
+        (i, 'G') => (&value[..i], 30),
+        (i, 'T') => (&value[..i], 40),
+        _ => (value, 0),
+    };
+    let size = digits.trim().parse::<u64>().ok()?;
+    if size == 0 || size.leading_zeros() < shift {
+        return None;
+    }
+    Some(size << shift)
+}
+
+/// Parses a `--cpu-rate` percentage, like "25" or "25%". Fractions are allowed
+/// down to 0.01%, which is what the job object can express. Returns the rate in
+/// hundredths of a percent, the unit JOBOBJECT_CPU_RATE_CONTROL_INFORMATION
+/// takes.
+pub fn parse_cpu_rate(value: &str) -> Option<u32> {
+    let value = value.trim();
+    let percent = value
+        .strip_suffix('%')
+        .unwrap_or(value)
+        .parse::<f64>()
+        .ok()?;
+    let rate = (percent * 100.0).round();
+    (1.0..=10000.0).contains(&rate).then_some(rate as u32)
+}
+
+/// What sudo exits with when the target hits its `--timeout`, no matter what
+/// the target itself exited with. This is ERROR_TIMEOUT, so that it's easy to
+/// tell apart from the target's own exit codes.
+pub const TIMEOUT_EXIT_CODE: i32 = ERROR_TIMEOUT.0 as i32;
+
+/// How long a target gets to exit after we've sent it a Ctrl+Break for hitting
+/// its `--timeout`, before we kill it.
+pub const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Parses a `--timeout` like "90", "30s", "5m", "1h", or "500ms". A plain
+/// number is in seconds. It has to fit in a DWORD of milliseconds (about 49
+/// days), since that's what we send over RPC. That doesn't include INFINITE,
+/// which would mean we never time out at all.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim().to_ascii_lowercase();
+    let (number, unit_ms) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 1)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1000)
+    } else if let Some(number) = value.strip_suffix('m') {
+        (number, 60 * 1000)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 60 * 60 * 1000)
+    } else {
+        (value.as_str(), 1000)
+    };
+    let ms = number.trim().parse::<u32>().ok()?.checked_mul(unit_ms)?;
+    (ms != 0 && ms != INFINITE).then(|| Duration::from_millis(ms.into()))
+}
+
+/// Parses an `--affinity` mask. This is either a hex mask ("0x0f"), or a
+/// list of CPU numbers and ranges ("0,2-3").
+pub fn parse_affinity(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let mask = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()?
+    } else {
+        let mut mask = 0u64;
+        for part in value.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (first, last),
+                None => (part, part),
+            };
+            let first = first.trim().parse::<u32>().ok()?;
+            let last = last.trim().parse::<u32>().ok()?;
+            if first > last || last >= u64::BITS {
+                return None;
+            }
+            for cpu in first..=last {
+                mask |= 1 << cpu;
+            }
+        }
+        mask
+    };
+    (mask != 0).then_some(mask)
+}
+
 // There can be many different types that need to be LocalFree'd. PWSTR, PCWSTR, PSTR, PCSTR, PSECURITY_DESCRIPTOR
 // are all distinct types, but they are compatible with the windows::core::IntoParam<HLOCAL> trait.
 // There's also *mut ACL though which is also LocalAlloc'd and that's the problem (probably not the last of its kind).
@@ -114,17 +285,8 @@ pub fn generate_rpc_endpoint_name(pid: u32, nonce: u32) -> String {
 }
 
 pub fn is_running_elevated() -> Result<bool> {
-    // TODO!
-    // Do the thing Terminal does to see if UAC is entirely disabled:
-    // Which is basically (from Utils::CanUwpDragDrop)
-    //     const auto elevationType = wil::get_token_information<TOKEN_ELEVATION_TYPE>(processToken.get());
-    //     const auto elevationState = wil::get_token_information<TOKEN_ELEVATION>(processToken.get());
-    //     if (elevationType == TokenElevationTypeDefault && elevationState.TokenIsElevated)
-    //
-
-    let current_token = current_process_token()?;
-    let elevation: TOKEN_ELEVATION = get_token_info(*current_token)?;
-    Ok(elevation.TokenIsElevated == 1)
+    // This is true for an admin with UAC off too. See get_elevation_state.
+    CurrentTokenProvider::default().is_elevated()
 }
 
 fn current_process_token() -> Result<Owned<HANDLE>> {
@@ -178,10 +340,97 @@ fn get_token_info<T: TokenInfo>(token: HANDLE) -> Result<T> {
     }
 }
 
-pub fn can_current_user_elevate() -> Result<bool> {
-    let current_token = current_process_token()?;
-    let elevation_type: TOKEN_ELEVATION_TYPE = get_token_info(*current_token)?;
-    Ok(elevation_type == TokenElevationTypeFull || elevation_type == TokenElevationTypeLimited)
+/// Checks if some other token (not ours) is elevated.
+pub fn is_token_elevated(token: HANDLE) -> Result<bool> {
+    let elevation: TOKEN_ELEVATION = get_token_info(token)?;
+    Ok(elevation.TokenIsElevated == 1)
+}
+
+/// The parts of a token that decide whether we can elevate. This is a trait
+/// so that the tests can fake it.
+pub trait TokenProvider {
+    fn elevation_type(&self) -> Result<TOKEN_ELEVATION_TYPE>;
+    fn is_elevated(&self) -> Result<bool>;
+}
+
+/// Our own process token.
+#[derive(Default)]
+pub struct CurrentTokenProvider {}
+
+impl TokenProvider for CurrentTokenProvider {
+    fn elevation_type(&self) -> Result<TOKEN_ELEVATION_TYPE> {
+        let current_token = current_process_token()?;
+        get_token_info(*current_token)
+    }
+    fn is_elevated(&self) -> Result<bool> {
+        let current_token = current_process_token()?;
+        let elevation: TOKEN_ELEVATION = get_token_info(*current_token)?;
+        Ok(elevation.TokenIsElevated == 1)
+    }
+}
+
+/// Where the current user stands, as far as elevating goes.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ElevationState {
+    /// We already have a full admin token, so we can just run the target
+    /// ourselves. That's an elevated admin, but also any admin when UAC is
+    /// off, and the built-in Administrator account.
+    AlreadyElevated,
+    /// An admin, running with their filtered (split) token. This is the normal
+    /// case.
+    CanElevate,
+    /// A standard user, who can elevate by having an admin enter their
+    /// credentials. Only allowed by policy.
+    CanElevateOverTheShoulder,
+    /// A standard user, who isn't allowed to elevate.
+    Disallowed,
+    /// A standard user, on a machine with UAC turned off. There's no way to
+    /// elevate at all here. The runas verb would just run things as them.
+    UacDisabled,
+}
+
+/// Works out the ElevationState for a token. This is "the thing Terminal
+/// does" to detect UAC being off: when it is, there's no split token, so the
+/// elevation type is always TokenElevationTypeDefault, and only
+/// TokenIsElevated says if we're an admin.
+pub fn get_elevation_state(
+    token: &impl TokenProvider,
+    uac_enabled: bool,
+    over_the_shoulder_allowed: bool,
+) -> Result<ElevationState> {
+    if token.is_elevated()? {
+        return Ok(ElevationState::AlreadyElevated);
+    }
+    let elevation_type = token.elevation_type()?;
+    Ok(
+        if elevation_type == TokenElevationTypeFull || elevation_type == TokenElevationTypeLimited {
+            ElevationState::CanElevate
+        } else if !uac_enabled {
+            ElevationState::UacDisabled
+        } else if over_the_shoulder_allowed {
+            ElevationState::CanElevateOverTheShoulder
+        } else {
+            ElevationState::Disallowed
+        },
+    )
+}
+
+/// Checks whether UAC is turned on (EnableLUA). If it's not set at all, UAC
+/// is on.
+fn is_uac_enabled() -> bool {
+    windows_registry::LOCAL_MACHINE
+        .open("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Policies\\System")
+        .and_then(|key| key.get_u32("EnableLUA"))
+        .map_or(true, |value| value != 0)
+}
+
+pub fn get_current_elevation_state() -> Result<ElevationState> {
+    let config: RegistryConfigProvider = Default::default();
+    get_elevation_state(
+        &CurrentTokenProvider::default(),
+        is_uac_enabled(),
+        is_policy_enabled(&config, POLICY_ALLOW_OVER_THE_SHOULDER),
+    )
 }
 
 pub fn get_sid_for_process(process: HANDLE) -> Result<SE_TOKEN_USER_1> {
@@ -190,9 +439,10 @@ pub fn get_sid_for_process(process: HANDLE) -> Result<SE_TOKEN_USER_1> {
     Ok(token_user.Anonymous2)
 }
 
-pub fn get_current_user() -> Result<HSTRING> {
+/// Gets the (string) SID of the user that a process is running as.
+pub fn get_user_for_process(process: HANDLE) -> Result<HSTRING> {
     unsafe {
-        let user = get_sid_for_process(GetCurrentProcess())?;
+        let user = get_sid_for_process(process)?;
 
         let mut str_sid = OwnedLocalAlloc::default();
         ConvertSidToStringSidW(PSID(&user.Sid as *const _ as _), &mut *str_sid)?;
@@ -201,6 +451,36 @@ pub fn get_current_user() -> Result<HSTRING> {
     }
 }
 
+pub fn get_user_for_pid(pid: u32) -> Result<HSTRING> {
+    let process =
+        unsafe { Owned::new(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?) };
+    get_user_for_process(*process)
+}
+
+pub fn get_current_user() -> Result<HSTRING> {
+    unsafe { get_user_for_process(GetCurrentProcess()) }
+}
+
+/// helper to find the process creation time for a given process handle
+/// process_handle: handle to the process to get the creation time for. This is a non-owning handle.
+pub fn get_process_creation_time(process_handle: HANDLE) -> Result<FILETIME> {
+    unsafe {
+        // You actually have to pass in valid pointers to these, even if we don't need them.
+        let mut creation_time = FILETIME::default();
+        let mut exit_time = FILETIME::default();
+        let mut kernel_time = FILETIME::default();
+        let mut user_time = FILETIME::default();
+        GetProcessTimes(
+            process_handle,
+            &mut creation_time,
+            &mut exit_time,
+            &mut kernel_time,
+            &mut user_time,
+        )?;
+        Ok(creation_time)
+    }
+}
+
 pub fn is_cmd_intrinsic(application: &str) -> bool {
     // List from https://ss64.com/nt/syntax-internal.html
     //
@@ -311,6 +591,25 @@ pub fn env_from_raw_bytes(env_string: &str) -> impl Iterator<Item = (&OsStr, &Os
     })
 }
 
+/// Builds an environment block for CreateProcessW (with
+/// CREATE_UNICODE_ENVIRONMENT) out of a null-delimited environment string.
+pub fn env_block_from_raw_bytes(env_string: &str) -> Vec<u16> {
+    let mut block = Vec::with_capacity(env_string.len() + 2);
+    for (key, value) in env_from_raw_bytes(env_string) {
+        block.extend(key.encode_wide());
+        block.push(b'=' as u16);
+        block.extend(value.encode_wide());
+        block.push(0);
+    }
+    // The block ends with an empty string. If there weren't any variables,
+    // that still needs to be two nulls.
+    if block.is_empty() {
+        block.push(0);
+    }
+    block.push(0);
+    block
+}
+
 /// Windows does not actually support distinct command line parameters. They're all just given as a single string.
 /// We can't just use `.join(" ")` either, because this breaks arguments with whitespaces. This function handles these details.
 pub fn join_args<T: AsRef<str>>(args: &[T]) -> String {
@@ -365,6 +664,73 @@ pub fn join_args<T: AsRef<str>>(args: &[T]) -> String {
     unsafe { String::from_utf8_unchecked(accumulator) }
 }
 
+/// Splits a single commandline string up into its arguments. This follows the
+/// same rules the MSVC CRT uses to build `argv`, which makes it the inverse of
+/// `join_args`.
+pub fn split_commandline(commandline: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_arg = false;
+    let mut in_quotes = false;
+    let mut backslashes: usize = 0;
+
+    for c in commandline.chars() {
+        match c {
+            '\\' => {
+                backslashes += 1;
+                in_arg = true;
+            }
+            '"' => {
+                // 2n backslashes followed by a quote are n backslashes and the
+                // start (or end) of a quoted section. 2n+1 backslashes followed
+                // by a quote are n backslashes and a literal quote.
+                current.extend((0..backslashes / 2).map(|_| '\\'));
+                if backslashes % 2 == 1 {
+                    current.push('"');
+                } else {
+                    in_quotes = !in_quotes;
+                }
+                backslashes = 0;
+                in_arg = true;
+            }
+            ' ' | '\t' if !in_quotes => {
+                current.extend((0..backslashes).map(|_| '\\'));
+                backslashes = 0;
+                if in_arg {
+                    args.push(std::mem::take(&mut current));
+                    in_arg = false;
+                }
+            }
+            _ => {
+                current.extend((0..backslashes).map(|_| '\\'));
+                backslashes = 0;
+                current.push(c);
+                in_arg = true;
+            }
+        }
+    }
+
+    current.extend((0..backslashes).map(|_| '\\'));
+    if in_arg {
+        args.push(current);
+    }
+    args
+}
+
+/// Splits the contents of a batch file up into the commandlines it contains.
+/// Each non-empty line is one command. Lines starting with `#` are comments.
+/// Returns the (1-based) line number of each command along with its args.
+pub fn parse_batch_file(contents: &str) -> Vec<(usize, Vec<String>)> {
+    contents
+        .trim_start_matches('\u{feff}')
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(line_number, line)| (line_number, split_commandline(line)))
+        .collect()
+}
+
 /// Joins a list of strings into a single string, each of which is null-terminated (including the final one).
 pub fn pack_string_list_for_rpc<T: AsRef<str>>(args: &[T]) -> String {
     let expected_len = args
@@ -393,9 +759,59 @@ pub fn unpack_string_list_from_rpc(args: Utf8Str) -> Result<Vec<String>> {
         .collect())
 }
 
+/// Packs the size of a pseudoconsole into a DWORD, like MAKELONG(columns,
+/// rows). No pseudoconsole at all packs to 0.
+pub fn pack_coord_for_rpc(size: Option<COORD>) -> u32 {
+    size.map_or(0, |size| {
+        (size.X as u16 as u32) | ((size.Y as u16 as u32) << 16)
+    })
+}
+
+/// Unpacks a size packed by `pack_coord_for_rpc`.
+pub fn unpack_coord_from_rpc(packed: u32) -> Option<COORD> {
+    (packed != 0).then_some(COORD {
+        X: packed as u16 as i16,
+        Y: (packed >> 16) as u16 as i16,
+    })
+}
+
+/// Policy value that allows `sudo --system`. Off unless it's set to 1.
+pub const POLICY_ALLOW_SYSTEM: &str = "AllowRunAsSystem";
+/// Policy value that allows `sudo --trusted-installer`. This is separate from
+/// POLICY_ALLOW_SYSTEM, since TrustedInstaller can do even more than SYSTEM.
+pub const POLICY_ALLOW_TRUSTED_INSTALLER: &str = "AllowRunAsTrustedInstaller";
+/// Policy value that allows standard users to use sudo, by having an admin
+/// enter their credentials (over-the-shoulder elevation).
+pub const POLICY_ALLOW_OVER_THE_SHOULDER: &str = "AllowOverTheShoulderElevation";
+/// Policy value that lets a target in disabled input mode read a redirected
+/// stdin, like `type script.sql | sudo sqlcmd`. It still never gets any
+/// console input.
+pub const POLICY_ALLOW_REDIRECTED_INPUT: &str = "AllowRedirectedInputWhenInputDisabled";
+/// Policy value that records what elevated sessions print, so that an admin
+/// can play it back later with `sudo replay`.
+pub const POLICY_RECORD_SESSIONS: &str = "RecordElevatedSessions";
+
+/// Setting that opens new windows in Windows Terminal, instead of conhost.
+pub const SETTING_USE_WINDOWS_TERMINAL: &str = "UseWindowsTerminal";
+/// Setting for which Windows Terminal profile to open new windows with. If it
+/// isn't set, they get the default profile.
+pub const SETTING_WINDOWS_TERMINAL_PROFILE: &str = "WindowsTerminalProfile";
+
 pub trait ConfigProvider {
     fn get_setting_mode(&self) -> Result<u32>;
     fn get_policy_mode(&self) -> Result<u32>;
+    /// Reads some other string from the settings key.
+    fn get_setting_string(&self, _name: &str) -> Result<String> {
+        Err(E_FILENOTFOUND.into())
+    }
+    /// Reads some other DWORD from the settings key.
+    fn get_setting_value(&self, _name: &str) -> Result<u32> {
+        Err(E_FILENOTFOUND.into())
+    }
+    /// Reads some other DWORD from the policy key.
+    fn get_policy_value(&self, _name: &str) -> Result<u32> {
+        Err(E_FILENOTFOUND.into())
+    }
 }
 
 #[derive(Default)]
@@ -406,11 +822,170 @@ impl ConfigProvider for RegistryConfigProvider {
             .open("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Sudo")
             .and_then(|key| key.get_u32("Enabled"))
     }
+    fn get_setting_string(&self, name: &str) -> Result<String> {
+        windows_registry::LOCAL_MACHINE
+            .open("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Sudo")
+            .and_then(|key| key.get_string(name))
+    }
+    fn get_setting_value(&self, name: &str) -> Result<u32> {
+        windows_registry::LOCAL_MACHINE
+            .open("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Sudo")
+            .and_then(|key| key.get_u32(name))
+    }
     fn get_policy_mode(&self) -> Result<u32> {
         windows_registry::LOCAL_MACHINE
             .open("SOFTWARE\\Policies\\Microsoft\\Windows\\Sudo")
             .and_then(|key| key.get_u32("Enabled"))
     }
+    fn get_policy_value(&self, name: &str) -> Result<u32> {
+        windows_registry::LOCAL_MACHINE
+            .open("SOFTWARE\\Policies\\Microsoft\\Windows\\Sudo")
+            .and_then(|key| key.get_u32(name))
+    }
+}
+
+/// The policy that needs to be enabled to run a command as `run_as`, if any.
+pub fn run_as_policy(run_as: RunAs) -> Option<&'static str> {
+    match run_as {
+        RunAs::Default | RunAs::Unelevated => None,
+        RunAs::System => Some(POLICY_ALLOW_SYSTEM),
+        RunAs::TrustedInstaller => Some(POLICY_ALLOW_TRUSTED_INSTALLER),
+    }
+}
+
+/// Checks one of the policy switches that turn on an (otherwise disabled)
+/// feature, like POLICY_ALLOW_SYSTEM. If the policy isn't set, or we fail to
+/// read it, the feature stays off.
+pub fn is_policy_enabled(config: &impl ConfigProvider, name: &str) -> bool {
+    matches!(config.get_policy_value(name), Ok(value) if value != 0)
+}
+
+/// Checks if a target in disabled input mode can have a stdin of this type.
+/// Only pipes and files are ever allowed: a character device could be the
+/// console.
+pub fn is_redirected_input_allowed(config: &impl ConfigProvider, file_type: FILE_TYPE) -> bool {
+    matches!(file_type, FILE_TYPE_PIPE | FILE_TYPE_DISK)
+        && is_policy_enabled(config, POLICY_ALLOW_REDIRECTED_INPUT)
+}
+
+/// Checks if an admin wants elevated sessions recorded. That's only possible
+/// if this sudo knows how to record them.
+pub fn is_recording_enabled(config: &impl ConfigProvider) -> bool {
+    cfg!(feature = "Feature_iolog") && is_policy_enabled(config, POLICY_RECORD_SESSIONS)
+}
+
+/// Checks if new windows should open in Windows Terminal. If so, returns the
+/// profile to use, which is empty for the default one. If the setting isn't
+/// there, or we fail to read it, they get conhost.
+pub fn get_windows_terminal_setting(config: &impl ConfigProvider) -> Option<String> {
+    if !cfg!(feature = "Feature_wt")
+        || !matches!(config.get_setting_value(SETTING_USE_WINDOWS_TERMINAL), Ok(value) if value != 0)
+    {
+        return None;
+    }
+    Some(
+        config
+            .get_setting_string(SETTING_WINDOWS_TERMINAL_PROFILE)
+            .unwrap_or_default(),
+    )
+}
+
+/// Where session recordings go: `%ProgramData%\Microsoft\Windows\Sudo\Recordings`.
+pub fn recordings_dir() -> Result<PathBuf> {
+    let program_data = unsafe {
+        let path = SHGetKnownFolderPath(&FOLDERID_ProgramData, KF_FLAG_DEFAULT, None)?;
+        let program_data = path.to_string();
+        CoTaskMemFree(Some(path.0 as _));
+        program_data?
+    };
+    Ok(PathBuf::from(program_data).join("Microsoft\\Windows\\Sudo\\Recordings"))
+}
+
+/// Creates the recordings directory, if it isn't there already. Only admins
+/// and SYSTEM get into it. The recordings are no use if the people being
+/// recorded can edit them.
+pub fn create_recordings_dir() -> Result<PathBuf> {
+    let dir = recordings_dir()?;
+    if let Some(parent) = dir.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    unsafe {
+        let mut sd: OwnedLocalAlloc<PSECURITY_DESCRIPTOR> = Default::default();
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            w!("D:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)"),
+            SDDL_REVISION,
+            &mut *sd,
+            None,
+        )?;
+        let attributes = SECURITY_ATTRIBUTES {
+            nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: sd.0 .0,
+            bInheritHandle: false.into(),
+        };
+        match CreateDirectoryW(&HSTRING::from(dir.as_path()), Some(&attributes)) {
+            Err(err) if err.code() == ERROR_ALREADY_EXISTS.to_hresult() => {
+                check_recordings_dir_security(&dir)?
+            }
+            Err(err) => return Err(err),
+            Ok(()) => {}
+        }
+    }
+    Ok(dir)
+}
+
+/// Makes sure that a recordings directory that was already there is one that
+/// only admins and SYSTEM can get into, like create_recordings_dir would have
+/// made it. Whoever else made it could edit the recordings, or keep them from
+/// being written at all.
+fn check_recordings_dir_security(dir: &Path) -> Result<()> {
+    fn is_admin_or_system(sid: PSID) -> bool {
+        unsafe {
+            IsWellKnownSid(sid, WinLocalSystemSid).as_bool()
+                || IsWellKnownSid(sid, WinBuiltinAdministratorsSid).as_bool()
+        }
+    }
+
+    unsafe {
+        let mut owner = PSID::default();
+        let mut dacl: *mut ACL = std::ptr::null_mut();
+        let mut sd: OwnedLocalAlloc<PSECURITY_DESCRIPTOR> = Default::default();
+        GetNamedSecurityInfoW(
+            &HSTRING::from(dir),
+            SE_FILE_OBJECT,
+            OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION,
+            Some(&mut owner),
+            None,
+            Some(&mut dacl),
+            None,
+            &mut *sd,
+        )
+        .ok()?;
+
+        let insecure = || -> Result<()> {
+            trace_log_message(&format!("{dir:?} isn't only for admins"));
+            Err(E_ACCESSDENIED.into())
+        };
+        // A NULL DACL lets everyone in.
+        if !is_admin_or_system(owner) || dacl.is_null() {
+            return insecure();
+        }
+        for index in 0..(*dacl).AceCount {
+            let mut ace = std::ptr::null_mut();
+            GetAce(dacl, index.into(), &mut ace)?;
+            let secure = match u32::from((*(ace as *const ACE_HEADER)).AceType) {
+                ACCESS_DENIED_ACE_TYPE => true,
+                ACCESS_ALLOWED_ACE_TYPE => {
+                    let ace = &*(ace as *const ACCESS_ALLOWED_ACE);
+                    is_admin_or_system(PSID(&ace.SidStart as *const _ as _))
+                }
+                _ => false,
+            };
+            if !secure {
+                return insecure();
+            }
+        }
+    }
+    Ok(())
 }
 
 /// Get the current mode allowed by policy.
@@ -475,8 +1050,46 @@ pub fn get_process_path_from_handle(process: HANDLE) -> Result<PathBuf> {
     }
 }
 
-/// Check that the client process is the same as the server process.
-pub fn check_client(client_handle: HANDLE) -> Result<()> {
+/// What the elevated sudo knows about the client that started it. We record
+/// this as soon as we start, while the client is still waiting on us, and then
+/// only accept RPC calls from that exact process.
+pub struct ExpectedClient {
+    pub pid: u32,
+    /// Along with the PID, this makes sure it's the same process, and not
+    /// some other one that happened to get the same PID later.
+    pub creation_time: FILETIME,
+    pub sid: SE_TOKEN_USER_1,
+    /// If this is set, the client can be a different user than us. That's
+    /// over-the-shoulder elevation, where a standard user had an admin type in
+    /// their credentials. Only allowed by policy.
+    pub other_user_allowed: bool,
+    /// The one request we'll run for the client. This is None for a batch,
+    /// where the client sends us a request per command. A batch client always
+    /// has to be the same user as us.
+    pub approved: Option<ApprovedRequest>,
+}
+
+impl ExpectedClient {
+    pub fn from_pid(
+        pid: u32,
+        other_user_allowed: bool,
+        approved: Option<ApprovedRequest>,
+    ) -> Result<Self> {
+        let process =
+            unsafe { Owned::new(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?) };
+        Ok(Self {
+            pid,
+            creation_time: get_process_creation_time(*process)?,
+            sid: get_sid_for_process(*process)?,
+            other_user_allowed,
+            approved,
+        })
+    }
+}
+
+/// Check that the client process is the same as the server process, and that
+/// it's the one that started us.
+pub fn check_client(client_handle: HANDLE, expected: &ExpectedClient) -> Result<()> {
     // Open a handle to the provided process
     let process_path = get_process_path_from_handle(client_handle)?;
     let our_path = std::env::current_exe().unwrap();
@@ -485,29 +1098,111 @@ pub fn check_client(client_handle: HANDLE) -> Result<()> {
     ));
 
     // Now, is this path the same as us? (ignoring case)
+    //
+    // If it isn't, it might still be us, under another name. We ship hard
+    // links to sudo.exe (like sudoedit.exe), and the client could've been
+    // started through a different one than we were.
     if !process_path
         .as_os_str()
         .eq_ignore_ascii_case(our_path.as_os_str())
+        && !is_same_file(&process_path, &our_path)?
     {
         return Err(E_ACCESSDENIED.into());
     }
 
+    // Is this the process that started us? The PID alone isn't enough, that
+    // could've been reused.
+    if get_process_creation_time(client_handle)? != expected.creation_time {
+        return Err(E_ACCESSDENIED.into());
+    }
+
     let mut client_sid = get_sid_for_process(client_handle)?;
+    let mut expected_sid = expected.sid;
     let mut our_sid = unsafe { get_sid_for_process(GetCurrentProcess())? };
-    // Are these SIDs the same? This check prevents over-the-shoulder elevation
-    // when the RPC server is in use.
     unsafe {
         // If the SID structures are equal, the return value is nonzero (TRUE)
         // Then the windows-rs projection will take the true and convert that to Ok(()), or FALSE to Err(GetLastError())
         // EqualSid(PSID{ 0: &mut client_sid.Sid as *mut _ } , &mut our_sid.Sid as *mut _ as PSID)?;
         let client_psid: PSID = PSID(&mut client_sid.Buffer as *mut _ as _);
+        let expected_psid: PSID = PSID(&mut expected_sid.Buffer as *mut _ as _);
         let our_psid: PSID = PSID(&mut our_sid.Buffer as *mut _ as _);
-        EqualSid(client_psid, our_psid)?;
+
+        // Is it still the same user that started us?
+        EqualSid(client_psid, expected_psid)?;
+
+        // Are these SIDs the same? This check prevents over-the-shoulder
+        // elevation when the RPC server is in use, unless the policy allows
+        // it.
+        if !expected.other_user_allowed {
+            EqualSid(client_psid, our_psid)?;
+        }
     };
 
     Ok(())
 }
 
+/// Checks if two paths are the same file on disk. Hard links to a file all have
+/// different paths, but they share the same volume and file ID.
+///
+/// This needs the full 128-bit file ID. The 64-bit file index from
+/// GetFileInformationByHandle isn't guaranteed to be unique on ReFS.
+pub fn is_same_file(a: &Path, b: &Path) -> Result<bool> {
+    fn file_id(path: &Path) -> Result<FILE_ID_INFO> {
+        // We don't need to read the file, just its attributes.
+        let file = std::fs::OpenOptions::new()
+            .access_mode(FILE_READ_ATTRIBUTES.0)
+            .open(path)?;
+        let mut info = FILE_ID_INFO::default();
+        unsafe {
+            GetFileInformationByHandleEx(
+                HANDLE(file.as_raw_handle() as _),
+                FileIdInfo,
+                &mut info as *mut _ as *mut _,
+                size_of::<FILE_ID_INFO>() as u32,
+            )?
+        };
+        Ok(info)
+    }
+    Ok(file_id(a)? == file_id(b)?)
+}
+
+/// The subcommands that sudo uses to run itself. When we start another copy of
+/// ourselves, it gets started under the same name we were, so these need to
+/// work no matter what that name was.
+const INTERNAL_SUBCOMMANDS: [&str; 4] = ["elevate", "read", "write", "tee"];
+
+/// We can be installed under other names, as hard links to sudo.exe. This
+/// returns the subcommand that the name we were invoked with stands for, if
+/// it's one of those:
+/// * `sudoedit` is `sudo edit`
+/// * `gsudo` is `sudo run`, for scripts that were written for gsudo
+pub fn subcommand_for_exe_name(argv0: &OsStr) -> Option<&'static str> {
+    let name = Path::new(argv0).file_stem()?.to_str()?;
+    match name.to_ascii_lowercase().as_str() {
+        "sudoedit" => Some("edit"),
+        "gsudo" => Some("run"),
+        _ => None,
+    }
+}
+
+/// Adjusts our commandline for the name we were invoked with, by adding the
+/// subcommand that it stands for (see `subcommand_for_exe_name`).
+pub fn args_for_exe_name(mut args: Vec<OsString>) -> Vec<OsString> {
+    let Some(subcommand) = args
+        .first()
+        .and_then(|argv0| subcommand_for_exe_name(argv0))
+    else {
+        return args;
+    };
+    let is_internal = args
+        .get(1)
+        .is_some_and(|arg| INTERNAL_SUBCOMMANDS.iter().any(|internal| arg == internal));
+    if !is_internal {
+        args.insert(1, subcommand.into());
+    }
+    args
+}
+
 /// Make a Windows path absolute, using GetFullPathNameW to resolve the file on disk.
 /// Largely lifted from the rust stdlib, because it's _currently_ a nightly-only function.
 /// We don't have all the same internal stdlib helpers they do, but it's effectively the same..
@@ -561,6 +1256,7 @@ pub fn get_exe_subsystem<P: AsRef<Path>>(path: P) -> Result<IMAGE_SUBSYSTEM> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use windows::Win32::Storage::FileSystem::FILE_TYPE_CHAR;
     use windows::Win32::System::Diagnostics::Debug::{
         IMAGE_SUBSYSTEM_WINDOWS_CUI, IMAGE_SUBSYSTEM_WINDOWS_GUI,
     };
@@ -583,6 +1279,93 @@ mod tests {
         assert_eq!(env_map[1], (OsStr::new("=D:"), OsStr::new("D:\\qux")));
     }
 
+    #[test]
+    fn test_env_block_from_raw_bytes() {
+        let block = env_block_from_raw_bytes("foo=bar\0=D:=D:\\qux\0\0");
+        let expected: Vec<u16> = "foo=bar\0=D:=D:\\qux\0\0".encode_utf16().collect();
+        assert_eq!(block, expected);
+
+        assert_eq!(env_block_from_raw_bytes(""), [0, 0]);
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("4096"), Some(4096));
+        assert_eq!(parse_memory_size("512K"), Some(512 << 10));
+        assert_eq!(parse_memory_size("512m"), Some(512 << 20));
+        assert_eq!(parse_memory_size("2GB"), Some(2 << 30));
+        assert_eq!(parse_memory_size("1T"), Some(1 << 40));
+        assert_eq!(parse_memory_size("0"), None);
+        assert_eq!(parse_memory_size("M"), None);
+        assert_eq!(parse_memory_size("-1M"), None);
+        assert_eq!(parse_memory_size("1.5G"), None);
+        assert_eq!(parse_memory_size("99999999999T"), None);
+    }
+
+    #[test]
+    fn test_parse_cpu_rate() {
+        assert_eq!(parse_cpu_rate("25"), Some(2500));
+        assert_eq!(parse_cpu_rate("25%"), Some(2500));
+        assert_eq!(parse_cpu_rate("100"), Some(10000));
+        assert_eq!(parse_cpu_rate("0.5"), Some(50));
+        assert_eq!(parse_cpu_rate("0"), None);
+        assert_eq!(parse_cpu_rate("101"), None);
+        assert_eq!(parse_cpu_rate("half"), None);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("1.5s"), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("2000h"), None);
+        assert_eq!(parse_duration("4294967295ms"), None);
+        assert_eq!(
+            parse_duration("4294967294ms"),
+            Some(Duration::from_millis(4294967294))
+        );
+    }
+
+    #[test]
+    fn test_parse_affinity() {
+        assert_eq!(parse_affinity("0x0f"), Some(0xf));
+        assert_eq!(parse_affinity("0"), Some(0b1));
+        assert_eq!(parse_affinity("0,2-3"), Some(0b1101));
+        assert_eq!(parse_affinity("63"), Some(1 << 63));
+        assert_eq!(parse_affinity("0x0"), None);
+        assert_eq!(parse_affinity("64"), None);
+        assert_eq!(parse_affinity("3-2"), None);
+        assert_eq!(parse_affinity("1,,2"), None);
+    }
+
+    #[test]
+    fn test_priority_class_from_name() {
+        for name in PRIORITY_NAMES {
+            assert!(priority_class_from_name(name).is_some(), "{name}");
+        }
+        assert_eq!(priority_class_from_name("idle"), Some(IDLE_PRIORITY_CLASS));
+        assert_eq!(priority_class_from_name("realtime"), None);
+    }
+
+    #[test]
+    fn test_is_allowed_priority_class() {
+        assert!(is_allowed_priority_class(HIGH_PRIORITY_CLASS.0));
+        assert!(is_allowed_priority_class(IDLE_PRIORITY_CLASS.0));
+        assert!(!is_allowed_priority_class(0));
+        assert!(!is_allowed_priority_class(REALTIME_PRIORITY_CLASS.0));
+        assert!(!is_allowed_priority_class(
+            (HIGH_PRIORITY_CLASS | IDLE_PRIORITY_CLASS).0
+        ));
+        assert!(!is_allowed_priority_class(
+            (HIGH_PRIORITY_CLASS | CREATE_SUSPENDED).0
+        ));
+    }
+
     #[test]
     fn test_join_args() {
         assert_eq!(join_args(&[""; 0]), "");
@@ -591,6 +1374,109 @@ mod tests {
         assert_eq!(join_args(&["f\\\"oo", "\"bar\""]), r#"f\\\"oo \"bar\""#);
     }
 
+    #[test]
+    fn test_split_commandline() {
+        assert_eq!(split_commandline(""), [""; 0]);
+        assert_eq!(split_commandline("  foo   bar "), ["foo", "bar"]);
+        assert_eq!(
+            split_commandline("cmd /c \"echo hi\""),
+            ["cmd", "/c", "echo hi"]
+        );
+        assert_eq!(split_commandline(r#"a\\\"b "c d"e"#), ["a\\\"b", "c de"]);
+        assert_eq!(
+            split_commandline(r"C:\foo\ \\server\share"),
+            [r"C:\foo\", r"\\server\share"]
+        );
+
+        // Everything join_args produces should split back into the same args.
+        let cases: [&[&str]; 4] = [
+            &["foo", "bar"],
+            &["f \too", " bar\t"],
+            &["f\\\"oo", "\"bar\""],
+            &["", r"C:\Program Files\", ""],
+        ];
+        for args in cases {
+            assert_eq!(split_commandline(&join_args(args)), args);
+        }
+    }
+
+    #[test]
+    fn test_parse_batch_file() {
+        let contents = "\u{feff}# set up the machine\r\n\r\nnet start w32time\r\n  --inline -D C:\\ \"my tool.exe\" /x  \r\n";
+        assert_eq!(
+            parse_batch_file(contents),
+            [
+                (3, vec!["net".to_string(), "start".into(), "w32time".into()]),
+                (
+                    4,
+                    vec![
+                        "--inline".to_string(),
+                        "-D".into(),
+                        "C:\\".into(),
+                        "my tool.exe".into(),
+                        "/x".into()
+                    ]
+                ),
+            ]
+        );
+        assert!(parse_batch_file("").is_empty());
+    }
+
+    #[test]
+    fn test_subcommand_for_exe_name() {
+        assert_eq!(subcommand_for_exe_name(OsStr::new("sudo")), None);
+        assert_eq!(
+            subcommand_for_exe_name(OsStr::new(r"C:\Windows\System32\sudo.exe")),
+            None
+        );
+        assert_eq!(
+            subcommand_for_exe_name(OsStr::new("sudoedit")),
+            Some("edit")
+        );
+        assert_eq!(
+            subcommand_for_exe_name(OsStr::new(r"C:\Windows\System32\SudoEdit.EXE")),
+            Some("edit")
+        );
+        assert_eq!(
+            subcommand_for_exe_name(OsStr::new("gsudo.exe")),
+            Some("run")
+        );
+    }
+
+    #[test]
+    fn test_args_for_exe_name() {
+        fn args(args: &[&str]) -> Vec<OsString> {
+            args.iter().map(OsString::from).collect()
+        }
+
+        // Plain sudo is left alone.
+        assert_eq!(
+            args_for_exe_name(args(&["sudo", "edit", "hosts"])),
+            args(&["sudo", "edit", "hosts"])
+        );
+        assert_eq!(
+            args_for_exe_name(args(&["sudoedit.exe", "hosts"])),
+            args(&["sudoedit.exe", "edit", "hosts"])
+        );
+        assert_eq!(
+            args_for_exe_name(args(&["gsudo", "netstat", "-ab"])),
+            args(&["gsudo", "run", "netstat", "-ab"])
+        );
+        assert_eq!(
+            args_for_exe_name(args(&["sudoedit"])),
+            args(&["sudoedit", "edit"])
+        );
+        // Internal subcommands work no matter what we're called.
+        assert_eq!(
+            args_for_exe_name(args(&["sudoedit", "elevate", "-p", "1", "-n", "2"])),
+            args(&["sudoedit", "elevate", "-p", "1", "-n", "2"])
+        );
+        assert_eq!(
+            args_for_exe_name(args(&["gsudo.exe", "read", "--", "hosts"])),
+            args(&["gsudo.exe", "read", "--", "hosts"])
+        );
+    }
+
     #[test]
     fn test_pack_args() {
         assert_eq!(pack_string_list_for_rpc(&[""; 0]), "");
@@ -598,6 +1484,19 @@ mod tests {
         assert_eq!(pack_string_list_for_rpc(&["foo", "bar"]), "foo\0bar\0");
     }
 
+    #[test]
+    fn test_pack_coord() {
+        assert_eq!(pack_coord_for_rpc(None), 0);
+        assert_eq!(unpack_coord_from_rpc(0), None);
+
+        let size = COORD { X: 120, Y: 30 };
+        assert_eq!(pack_coord_for_rpc(Some(size)), (30 << 16) | 120);
+        assert_eq!(
+            unpack_coord_from_rpc(pack_coord_for_rpc(Some(size))),
+            Some(size)
+        );
+    }
+
     #[test]
     fn test_unpack_args() {
         assert_eq!(unpack_string_list_from_rpc("".into()).unwrap(), [""; 0]);
@@ -624,6 +1523,33 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_is_same_file() {
+        let dir = std::env::temp_dir().join(format!("sudo_same_file_{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let (file, link, other) = (dir.join("a"), dir.join("b"), dir.join("c"));
+        std::fs::write(&file, "a").unwrap();
+        std::fs::hard_link(&file, &link).unwrap();
+        std::fs::write(&other, "a").unwrap();
+
+        assert!(is_same_file(&file, &link).unwrap());
+        assert!(!is_same_file(&file, &other).unwrap());
+        _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_check_recordings_dir_security() {
+        // Anyone could have made this one, and its DACL comes from the temp
+        // dir, which lets the user in.
+        let dir = std::env::temp_dir().join(format!("sudo_recordings_{}", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let result = check_recordings_dir_security(&dir);
+        _ = std::fs::remove_dir_all(&dir);
+        assert_eq!(result, Err(E_ACCESSDENIED.into()));
+    }
+
     #[test]
     fn test_get_exe_subsystem() {
         assert_eq!(
@@ -651,6 +1577,155 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_is_policy_enabled() {
+        // TestConfigProvider doesn't have any other policy values.
+        let config = TestConfigProvider {
+            setting_mode: Ok(3),
+            policy_mode: Ok(3),
+        };
+        assert!(!is_policy_enabled(&config, POLICY_ALLOW_SYSTEM));
+
+        struct PolicyValueProvider(Result<u32>);
+        impl ConfigProvider for PolicyValueProvider {
+            fn get_setting_mode(&self) -> Result<u32> {
+                Ok(3)
+            }
+            fn get_policy_mode(&self) -> Result<u32> {
+                Ok(3)
+            }
+            fn get_policy_value(&self, _name: &str) -> Result<u32> {
+                self.0.clone()
+            }
+        }
+        assert!(is_policy_enabled(
+            &PolicyValueProvider(Ok(1)),
+            POLICY_ALLOW_SYSTEM
+        ));
+        assert!(!is_policy_enabled(
+            &PolicyValueProvider(Ok(0)),
+            POLICY_ALLOW_SYSTEM
+        ));
+        assert!(!is_policy_enabled(
+            &PolicyValueProvider(Err(E_ACCESSDENIED.into())),
+            POLICY_ALLOW_SYSTEM
+        ));
+
+        let allowed = PolicyValueProvider(Ok(1));
+        assert!(is_redirected_input_allowed(&allowed, FILE_TYPE_PIPE));
+        assert!(is_redirected_input_allowed(&allowed, FILE_TYPE_DISK));
+        // The console is a character device.
+        assert!(!is_redirected_input_allowed(&allowed, FILE_TYPE_CHAR));
+        assert!(!is_redirected_input_allowed(&config, FILE_TYPE_PIPE));
+    }
+
+    #[test]
+    #[cfg(feature = "Feature_wt")]
+    fn test_windows_terminal_setting() {
+        // TestConfigProvider doesn't have any other settings.
+        let config = TestConfigProvider {
+            setting_mode: Ok(1),
+            policy_mode: Ok(3),
+        };
+        assert_eq!(get_windows_terminal_setting(&config), None);
+
+        struct SettingProvider(Result<u32>, Result<String>);
+        impl ConfigProvider for SettingProvider {
+            fn get_setting_mode(&self) -> Result<u32> {
+                Ok(1)
+            }
+            fn get_policy_mode(&self) -> Result<u32> {
+                Ok(3)
+            }
+            fn get_setting_value(&self, _name: &str) -> Result<u32> {
+                self.0.clone()
+            }
+            fn get_setting_string(&self, _name: &str) -> Result<String> {
+                self.1.clone()
+            }
+        }
+        let profile = || Ok("Admin PowerShell".to_string());
+        assert_eq!(
+            get_windows_terminal_setting(&SettingProvider(Ok(1), profile())),
+            Some("Admin PowerShell".to_string())
+        );
+        // No profile means the default one.
+        assert_eq!(
+            get_windows_terminal_setting(&SettingProvider(Ok(1), Err(E_FILENOTFOUND.into()))),
+            Some(String::new())
+        );
+        // The profile doesn't matter if it's turned off.
+        assert_eq!(
+            get_windows_terminal_setting(&SettingProvider(Ok(0), profile())),
+            None
+        );
+        assert_eq!(
+            get_windows_terminal_setting(&SettingProvider(Err(E_ACCESSDENIED.into()), profile())),
+            None
+        );
+    }
+
+    struct TestTokenProvider {
+        elevation_type: TOKEN_ELEVATION_TYPE,
+        is_elevated: bool,
+    }
+
+    impl TokenProvider for TestTokenProvider {
+        fn elevation_type(&self) -> Result<TOKEN_ELEVATION_TYPE> {
+            Ok(self.elevation_type)
+        }
+        fn is_elevated(&self) -> Result<bool> {
+            Ok(self.is_elevated)
+        }
+    }
+
+    #[test]
+    fn test_get_elevation_state() {
+        use ElevationState::*;
+
+        let admin = TestTokenProvider {
+            elevation_type: TokenElevationTypeLimited,
+            is_elevated: false,
+        };
+        let elevated_admin = TestTokenProvider {
+            elevation_type: TokenElevationTypeFull,
+            is_elevated: true,
+        };
+        // UAC off, or the built-in Administrator: no split token, but elevated.
+        let unsplit_admin = TestTokenProvider {
+            elevation_type: TokenElevationTypeDefault,
+            is_elevated: true,
+        };
+        let standard_user = TestTokenProvider {
+            elevation_type: TokenElevationTypeDefault,
+            is_elevated: false,
+        };
+
+        // (token, uac_enabled, over_the_shoulder_allowed, expected)
+        let table = [
+            (&admin, true, false, CanElevate),
+            (&admin, true, true, CanElevate),
+            (&elevated_admin, true, false, AlreadyElevated),
+            (&unsplit_admin, true, false, AlreadyElevated),
+            (&unsplit_admin, false, false, AlreadyElevated),
+            (&unsplit_admin, false, true, AlreadyElevated),
+            (&standard_user, true, false, Disallowed),
+            (&standard_user, true, true, CanElevateOverTheShoulder),
+            (&standard_user, false, false, UacDisabled),
+            // Even with the policy, there's nobody to enter credentials for.
+            (&standard_user, false, true, UacDisabled),
+        ];
+        for (i, (token, uac_enabled, over_the_shoulder_allowed, expected)) in
+            table.into_iter().enumerate()
+        {
+            assert_eq!(
+                get_elevation_state(token, uac_enabled, over_the_shoulder_allowed),
+                Ok(expected),
+                "row {i}"
+            );
+        }
+    }
+
     #[test]
     fn test_get_allowed_mode_from_policy() {
         // no setting at all
@@ -0,0 +1,409 @@
+//! Session recordings, in the asciicast v2 format
+//! (https://docs.asciinema.org/manual/asciicast/v2/). A recording is a line of
+//! JSON with a header, then one line for each chunk of output:
+//!
+//! ```text
+//! {"version": 2, "width": 120, "height": 30, "timestamp": 1718900000, "command": "cmd.exe"}
+//! [0.012345, "o", "Microsoft Windows [Version 10.0.22631.3737]\r\n"]
+//! ```
+//!
+//! This is only the format. Where the recordings go, and who gets to read
+//! them, is up to the elevated sudo (and `sudo replay`).
+
+use std::io::{self, Write};
+use std::time::Duration;
+use windows::core::GUID;
+
+/// The recording for a request is named after its event_id, so that the
+/// RequestID in the event log leads right to it.
+pub fn recording_file_name(event_id: &GUID) -> String {
+    format!("{event_id:?}.cast")
+}
+
+/// Parses the ID that `sudo replay` was given. That's the RequestID from the
+/// event log, which Event Viewer shows in braces, so we take it either way.
+pub fn parse_recording_id(id: &str) -> Option<GUID> {
+    let id = id.strip_prefix('{').unwrap_or(id);
+    let id = id.strip_suffix('}').unwrap_or(id);
+    let is_guid = id.len() == 36
+        && id.char_indices().all(|(i, c)| match i {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        });
+    // GUID::from panics on anything that isn't a GUID, so check first.
+    is_guid.then(|| GUID::from(id))
+}
+
+/// The first line of a recording.
+pub struct Header {
+    pub width: u16,
+    pub height: u16,
+    /// When the recording started, in seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub command: String,
+}
+
+/// Writes a recording, one chunk of output at a time.
+pub struct Recorder<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(mut out: W, header: &Header) -> io::Result<Self> {
+        writeln!(
+            out,
+            r#"{{"version": 2, "width": {}, "height": {}, "timestamp": {}, "command": {}}}"#,
+            header.width,
+            header.height,
+            header.timestamp,
+            json_string(&header.command)
+        )?;
+        Ok(Recorder { out })
+    }
+
+    /// Records `text` as having been printed `elapsed` after the recording
+    /// started.
+    pub fn output(&mut self, elapsed: Duration, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        writeln!(
+            self.out,
+            r#"[{:.6}, "o", {}]"#,
+            elapsed.as_secs_f64(),
+            json_string(text)
+        )?;
+        // If the elevated sudo goes away before the target does, we still
+        // want everything up to that point.
+        self.out.flush()
+    }
+}
+
+/// Turns the bytes a target prints into text for a recording. Output comes in
+/// whatever chunks the pipe gives us, which can end halfway through a
+/// character. Those bytes get held on to until the rest of the character
+/// shows up.
+#[derive(Default)]
+pub struct Utf8Chunker {
+    pending: Vec<u8>,
+}
+
+impl Utf8Chunker {
+    pub fn push(&mut self, data: &[u8]) -> String {
+        self.pending.extend_from_slice(data);
+        let mut text = String::new();
+        let mut rest = &self.pending[..];
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(err) => {
+                    let (valid, invalid) = rest.split_at(err.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match err.error_len() {
+                        // Not UTF-8 at all. There's nothing better to do with
+                        // it than what from_utf8_lossy would.
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        // The start of a character that hasn't all arrived
+                        // yet.
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        self.pending = rest.to_vec();
+        text
+    }
+}
+
+/// One chunk of output from a recording.
+#[derive(Debug, PartialEq)]
+pub struct Event {
+    /// Seconds since the recording started.
+    pub time: f64,
+    pub text: String,
+}
+
+/// Reads the output back out of a recording. Anything that isn't output (like
+/// input, or markers, which we never write) gets skipped. Returns None if it
+/// isn't an asciicast v2 recording.
+pub fn parse_recording(recording: &str) -> Option<Vec<Event>> {
+    let mut lines = recording.lines();
+    let header = lines.next()?;
+    if !header.replace(' ', "").contains(r#""version":2"#) {
+        return None;
+    }
+    let mut events = Vec::new();
+    for line in lines.filter(|line| !line.trim().is_empty()) {
+        let (time, code, text) = parse_event(line)?;
+        if code == "o" {
+            events.push(Event { time, text });
+        }
+    }
+    Some(events)
+}
+
+/// Parses `sudo replay --speed`: how many times faster than real time to play
+/// a recording back, like `2`, `0.5` or `10x`.
+pub fn parse_playback_speed(value: &str) -> Option<f64> {
+    let value = value.strip_suffix(['x', 'X']).unwrap_or(value);
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|speed| speed.is_finite() && *speed > 0.0)
+}
+
+/// How long to wait before playing the event at `next`, after the one at
+/// `previous`. `speed` of 2 plays it back twice as fast. A delay too long to
+/// fit in a Duration (say, at a speed of 1e-300) is as long as one can be.
+pub fn playback_delay(previous: f64, next: f64, speed: f64) -> Duration {
+    let delay = ((next - previous) / speed).max(0.0);
+    Duration::try_from_secs_f64(delay).unwrap_or(Duration::MAX)
+}
+
+/// Quotes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses an event line: `[time, "code", "data"]`. The time is in seconds
+/// since the recording started, so it can't be negative (or infinite).
+fn parse_event(line: &str) -> Option<(f64, String, String)> {
+    let rest = line.trim().strip_prefix('[')?.strip_suffix(']')?;
+    let (time, rest) = rest.split_once(',')?;
+    let time = time
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|time| time.is_finite() && *time >= 0.0)?;
+    let (code, rest) = parse_json_string(rest.trim_start())?;
+    let rest = rest.trim_start().strip_prefix(',')?;
+    let (data, rest) = parse_json_string(rest.trim_start())?;
+    rest.trim().is_empty().then_some((time, code, data))
+}
+
+/// Parses the JSON string at the start of `s`. Returns it, and whatever came
+/// after it.
+fn parse_json_string(s: &str) -> Option<(String, &str)> {
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut parsed = String::new();
+    // The first half of a surrogate pair, waiting for the second.
+    let mut high_surrogate = None;
+    while let Some((i, c)) = chars.next() {
+        let c = match c {
+            '"' => return Some((parsed, &s[i + 2..])),
+            '\\' => match chars.next()?.1 {
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = (0..4)
+                        .filter_map(|_| chars.next())
+                        .map(|(_, c)| c)
+                        .collect();
+                    let unit = u16::from_str_radix(&hex, 16).ok()?;
+                    if let Some(high) = high_surrogate.take() {
+                        parsed.extend(
+                            char::decode_utf16([high, unit])
+                                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER)),
+                        );
+                        continue;
+                    }
+                    if (0xD800..0xDC00).contains(&unit) {
+                        high_surrogate = Some(unit);
+                        continue;
+                    }
+                    char::from_u32(unit.into()).unwrap_or(char::REPLACEMENT_CHARACTER)
+                }
+                c => c,
+            },
+            c => c,
+        };
+        if high_surrogate.take().is_some() {
+            parsed.push(char::REPLACEMENT_CHARACTER);
+        }
+        parsed.push(c);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            width: 120,
+            height: 30,
+            timestamp: 1718900000,
+            command: r#"cmd.exe /c "echo hi""#.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_recorder() {
+        let mut recorder = Recorder::new(Vec::new(), &header()).unwrap();
+        recorder
+            .output(Duration::from_millis(12), "hello\r\n")
+            .unwrap();
+        recorder.output(Duration::from_millis(20), "").unwrap();
+        recorder
+            .output(Duration::from_micros(1_500_001), "\x1b[2J\"quoted\" \\ \t")
+            .unwrap();
+
+        let recording = String::from_utf8(recorder.out).unwrap();
+        let lines: Vec<_> = recording.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"version": 2, "width": 120, "height": 30, "timestamp": 1718900000, "command": "cmd.exe /c \"echo hi\""}"#,
+                r#"[0.012000, "o", "hello\r\n"]"#,
+                r#"[1.500001, "o", "\u001b[2J\"quoted\" \\ \t"]"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let mut recorder = Recorder::new(Vec::new(), &header()).unwrap();
+        let chunks = ["C:\\> dir\r\n", "😀 \x1b[31mred\x1b[0m", "\u{7f}\u{1}"];
+        for (i, chunk) in chunks.iter().enumerate() {
+            recorder
+                .output(Duration::from_secs(i as u64), chunk)
+                .unwrap();
+        }
+
+        let recording = String::from_utf8(recorder.out).unwrap();
+        let events = parse_recording(&recording).unwrap();
+        assert_eq!(events.len(), chunks.len());
+        for (i, (event, chunk)) in events.iter().zip(chunks).enumerate() {
+            assert_eq!(event.time, i as f64);
+            assert_eq!(event.text, chunk);
+        }
+    }
+
+    #[test]
+    fn test_parse_recording() {
+        let recording = concat!(
+            "{\"version\":2,\"width\":80,\"height\":24}\n",
+            "[0.5, \"o\", \"a\\u00e9\\ud83d\\ude00\"]\n",
+            "[0.75, \"i\", \"typed\"]\n",
+            "\n",
+            "[1.0,\"o\",\"b\"]\n",
+        );
+        assert_eq!(
+            parse_recording(recording).unwrap(),
+            [
+                Event {
+                    time: 0.5,
+                    text: "aé😀".to_string()
+                },
+                Event {
+                    time: 1.0,
+                    text: "b".to_string()
+                },
+            ]
+        );
+
+        // Not asciicast v2 at all.
+        assert_eq!(parse_recording(""), None);
+        assert_eq!(parse_recording("{\"version\": 1}\n"), None);
+        // A broken event.
+        assert_eq!(
+            parse_recording("{\"version\": 2}\n[0.5, \"o\", \"unterminated]\n"),
+            None
+        );
+        // Times that no recording could have.
+        for time in ["inf", "-inf", "NaN", "-0.5"] {
+            let recording = format!("{{\"version\": 2}}\n[{time}, \"o\", \"a\"]\n");
+            assert_eq!(parse_recording(&recording), None, "{time}");
+        }
+    }
+
+    #[test]
+    fn test_utf8_chunker() {
+        let mut chunker = Utf8Chunker::default();
+        assert_eq!(chunker.push(b"plain"), "plain");
+
+        // é is C3 A9. It can get split between two reads.
+        assert_eq!(chunker.push(b"caf\xC3"), "caf");
+        assert_eq!(chunker.push(b"\xA9!"), "é!");
+
+        // 😀 is F0 9F 98 80, split three ways.
+        assert_eq!(chunker.push(b"\xF0"), "");
+        assert_eq!(chunker.push(b"\x9F\x98"), "");
+        assert_eq!(chunker.push(b"\x80"), "😀");
+
+        // Bytes that were never going to be UTF-8 get replaced.
+        assert_eq!(chunker.push(b"a\xFFb"), "a\u{FFFD}b");
+    }
+
+    #[test]
+    fn test_parse_playback_speed() {
+        assert_eq!(parse_playback_speed("1"), Some(1.0));
+        assert_eq!(parse_playback_speed("0.5"), Some(0.5));
+        assert_eq!(parse_playback_speed("10x"), Some(10.0));
+        assert_eq!(parse_playback_speed("0"), None);
+        assert_eq!(parse_playback_speed("-2"), None);
+        assert_eq!(parse_playback_speed("inf"), None);
+        assert_eq!(parse_playback_speed("fast"), None);
+    }
+
+    #[test]
+    fn test_playback_delay() {
+        assert_eq!(playback_delay(1.0, 3.0, 1.0), Duration::from_secs(2));
+        assert_eq!(playback_delay(1.0, 3.0, 4.0), Duration::from_millis(500));
+        // Out of order events don't wait at all.
+        assert_eq!(playback_delay(3.0, 1.0, 1.0), Duration::ZERO);
+        // Delays too long for a Duration don't panic.
+        assert_eq!(playback_delay(0.0, 1e300, 1.0), Duration::MAX);
+        assert_eq!(playback_delay(0.0, 1.0, 1e-300), Duration::MAX);
+    }
+
+    #[test]
+    fn test_recording_ids() {
+        let id = GUID::from_u128(0x6ba7b810_9dad_11d1_80b4_00c04fd430c8);
+        assert_eq!(
+            recording_file_name(&id),
+            "6BA7B810-9DAD-11D1-80B4-00C04FD430C8.cast"
+        );
+        assert_eq!(
+            parse_recording_id("6ba7b810-9dad-11d1-80b4-00c04fd430c8"),
+            Some(id)
+        );
+        assert_eq!(
+            parse_recording_id("{6BA7B810-9DAD-11D1-80B4-00C04FD430C8}"),
+            Some(id)
+        );
+        assert_eq!(parse_recording_id("6BA7B810"), None);
+        assert_eq!(
+            parse_recording_id("..\\..\\9DAD-11D1-80B4-00C04FD430C8.cast"),
+            None
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::helpers::join_args;
+use crate::helpers::{join_args, RunAs};
 use crate::messages::ElevateRequest;
 use std::env;
 use std::ffi::CString;
@@ -13,6 +13,14 @@ extern "C" {
     static PROVIDER_GUID: GUID;
     static SudoRequestRunEvent: EVENT_DESCRIPTOR;
     static SudoRecieveRunRequestEvent: EVENT_DESCRIPTOR;
+    static SudoRequestBatchCommandEvent: EVENT_DESCRIPTOR;
+    static SudoReceiveBatchCommandEvent: EVENT_DESCRIPTOR;
+    static SudoRequestRunAsEvent: EVENT_DESCRIPTOR;
+    static SudoReceiveRunAsEvent: EVENT_DESCRIPTOR;
+    static SudoRestrictedTokenEvent: EVENT_DESCRIPTOR;
+    static SudoOverTheShoulderEvent: EVENT_DESCRIPTOR;
+    static SudoTimeoutEvent: EVENT_DESCRIPTOR;
+    static SudoDetachedEvent: EVENT_DESCRIPTOR;
 }
 
 #[repr(transparent)]
@@ -61,6 +69,18 @@ where
     }
 }
 
+/// Registers our provider and writes a single event with the given data.
+fn write_event(event: &EVENT_DESCRIPTOR, descriptors: &[EVENT_DATA_DESCRIPTOR]) {
+    let mut registration_handle = OwnedReghandle::default();
+    // The error code returned by EventRegister is primarily intended for use in debugging and diagnostic scenarios.
+    // Most production code should continue to run even if an ETW provider failed to register,
+    // so release builds should usually ignore the error code returned by EventRegister.
+    unsafe {
+        EventRegister(&PROVIDER_GUID, None, None, &mut *registration_handle);
+        EventWrite(*registration_handle, event, Some(descriptors));
+    }
+}
+
 /// Writes this request to the Windows Event Log. We do this for admins to be
 /// able to audit who's calling what with sudo.
 /// We log our messages to "Applications and Services Logs" -> "Microsoft" ->
@@ -69,12 +89,6 @@ where
 /// Alternatively, you can view this log with
 /// `wevtutil qe Microsoft-Windows-Sudo/Admin /c:3 /rd:true /f:text`
 pub fn event_log_request(is_client: bool, req: &ElevateRequest) {
-    let mut registration_handle = OwnedReghandle::default();
-    // The error code returned by EventRegister is primarily intended for use in debugging and diagnostic scenarios.
-    // Most production code should continue to run even if an ETW provider failed to register,
-    // so release builds should usually ignore the error code returned by EventRegister.
-    unsafe { EventRegister(&PROVIDER_GUID, None, None, &mut *registration_handle) };
-
     let application = str_to_cstr_vec(req.application.as_str());
     let args_len = req.args.len() as u32;
     let args: Vec<_> = req
@@ -85,7 +99,9 @@ pub fn event_log_request(is_client: bool, req: &ElevateRequest) {
     let cwd = str_to_cstr_vec(req.target_dir.as_str());
     let mode = req.sudo_mode as u32;
     let inherit_env = !req.env_vars.is_empty();
-    let redirected = req.handles.iter().any(|h| !h.is_invalid());
+    // With a pseudoconsole, the handles are just its pipes. The target's
+    // still using a console for everything.
+    let redirected = req.pseudoconsole.is_none() && req.handles.iter().any(|h| !h.is_invalid());
     let commandline = str_to_cstr_vec(format!(
         "{} {} {}",
         env::current_exe().unwrap().display(),
@@ -132,15 +148,161 @@ pub fn event_log_request(is_client: bool, req: &ElevateRequest) {
         size_of_val(&request_id),
     ));
 
-    unsafe {
-        // We're literally using the same data template for both requests and
-        // responses. The only difference is the event ID's have different keywords
-        // (to ID who sent the event).
-        let event_id = if is_client {
+    // We're literally using the same data template for both requests and
+    // responses. The only difference is the event ID's have different keywords
+    // (to ID who sent the event).
+    let event_id = unsafe {
+        if is_client {
             &SudoRequestRunEvent
         } else {
             &SudoRecieveRunRequestEvent
-        };
-        EventWrite(*registration_handle, event_id, Some(&descriptors));
+        }
+    };
+    write_event(event_id, &descriptors);
+
+    if req.batch_id != GUID::zeroed() {
+        event_log_batch_command(is_client, req);
+    }
+    if req.run_as != RunAs::Default {
+        event_log_run_as(is_client, req);
     }
 }
+
+/// Ties a request to the batch it was a part of. The RequestID here matches the
+/// one in the request's own event, and every command in the batch shares the
+/// same BatchID.
+fn event_log_batch_command(is_client: bool, req: &ElevateRequest) {
+    let request_id = req.event_id;
+    let batch_id = req.batch_id;
+
+    let descriptors = [
+        // <data name="RequestID" inType="win:GUID"/>
+        create_descriptor(addr_of!(request_id), size_of_val(&request_id)),
+        // <data name="BatchID" inType="win:GUID"/>
+        create_descriptor(addr_of!(batch_id), size_of_val(&batch_id)),
+    ];
+
+    let event_id = unsafe {
+        if is_client {
+            &SudoRequestBatchCommandEvent
+        } else {
+            &SudoReceiveBatchCommandEvent
+        }
+    };
+    write_event(event_id, &descriptors);
+}
+
+/// Records who the target of a request is going to run as, when that's
+/// someone other than the elevated user. TargetKind is the RunAs value from
+/// the request.
+fn event_log_run_as(is_client: bool, req: &ElevateRequest) {
+    let request_id = req.event_id;
+    let target_kind: u32 = req.run_as.into();
+    let target_user = str_to_cstr_vec(match req.run_as {
+        RunAs::Default => "",
+        RunAs::System => "NT AUTHORITY\\SYSTEM",
+        RunAs::TrustedInstaller => "NT SERVICE\\TrustedInstaller",
+        RunAs::Unelevated => "(unelevated)",
+    });
+
+    let descriptors = [
+        // <data name="RequestID" inType="win:GUID"/>
+        create_descriptor(addr_of!(request_id), size_of_val(&request_id)),
+        // <data name="TargetKind" inType="win:UInt32"/>
+        create_descriptor(addr_of!(target_kind), size_of_val(&target_kind)),
+        // <data name="TargetUser" inType="win:AnsiString" outType="win:Utf8"/>
+        create_descriptor(target_user.as_ptr(), target_user.len()),
+    ];
+
+    let event_id = unsafe {
+        if is_client {
+            &SudoRequestRunAsEvent
+        } else {
+            &SudoReceiveRunAsEvent
+        }
+    };
+    write_event(event_id, &descriptors);
+}
+
+/// Records the privileges the target of a request actually ended up with,
+/// after `--privilege` and `--drop-privilege` were applied. This is only
+/// written by whoever creates the target, once the token exists.
+pub fn event_log_privileges(req: &ElevateRequest, privileges: &[String]) {
+    let request_id = req.event_id;
+    let count = privileges.len() as u32;
+    let names: Vec<_> = privileges
+        .iter()
+        .map(|name| str_to_cstr_vec(name.as_str()))
+        .collect();
+
+    let mut descriptors = Vec::with_capacity(2 + names.len());
+    // <data name="RequestID" inType="win:GUID"/>
+    descriptors.push(create_descriptor(
+        addr_of!(request_id),
+        size_of_val(&request_id),
+    ));
+    // <data name="PrivilegeCount" inType="win:UInt32"/>
+    descriptors.push(create_descriptor(addr_of!(count), size_of_val(&count)));
+    // <data name="Privilege" inType="win:AnsiString" outType="win:Utf8" count="PrivilegeCount"/>
+    for name in &names {
+        descriptors.push(create_descriptor(name.as_ptr(), name.len()));
+    }
+
+    write_event(unsafe { &SudoRestrictedTokenEvent }, &descriptors);
+}
+
+/// Records both users involved in an over-the-shoulder elevation: the one who
+/// ran sudo, and the one whose credentials were entered to elevate it. Both
+/// are SIDs.
+pub fn event_log_over_the_shoulder(req: &ElevateRequest, requesting: &HSTRING, elevated: &HSTRING) {
+    let request_id = req.event_id;
+    let requesting = str_to_cstr_vec(requesting.to_string());
+    let elevated = str_to_cstr_vec(elevated.to_string());
+
+    let descriptors = [
+        // <data name="RequestID" inType="win:GUID"/>
+        create_descriptor(addr_of!(request_id), size_of_val(&request_id)),
+        // <data name="RequestingUser" inType="win:AnsiString" outType="win:Utf8"/>
+        create_descriptor(requesting.as_ptr(), requesting.len()),
+        // <data name="ElevatedUser" inType="win:AnsiString" outType="win:Utf8"/>
+        create_descriptor(elevated.as_ptr(), elevated.len()),
+    ];
+
+    write_event(unsafe { &SudoOverTheShoulderEvent }, &descriptors);
+}
+
+/// Records that we stopped a target for running past its `--timeout`.
+/// `graceful` is whether it exited on its own after the Ctrl+Break, before we
+/// had to kill it.
+pub fn event_log_timeout(req: &ElevateRequest, graceful: bool) {
+    let request_id = req.event_id;
+    let timeout_ms = req.timeout_ms();
+    let graceful = graceful as u8;
+
+    let descriptors = [
+        // <data name="RequestID" inType="win:GUID"/>
+        create_descriptor(addr_of!(request_id), size_of_val(&request_id)),
+        // <data name="TimeoutMs" inType="win:UInt32"/>
+        create_descriptor(addr_of!(timeout_ms), size_of_val(&timeout_ms)),
+        // <data name="Graceful" inType="win:UInt8" outType="xs:boolean"/>
+        create_descriptor(addr_of!(graceful), size_of_val(&graceful)),
+    ];
+
+    write_event(unsafe { &SudoTimeoutEvent }, &descriptors);
+}
+
+/// Records that we started a target in the background (`-b`). Once it's
+/// detached, nothing else we log will mention it, so this is the only record
+/// of which process it was.
+pub fn event_log_detached(req: &ElevateRequest, pid: u32) {
+    let request_id = req.event_id;
+
+    let descriptors = [
+        // <data name="RequestID" inType="win:GUID"/>
+        create_descriptor(addr_of!(request_id), size_of_val(&request_id)),
+        // <data name="ProcessID" inType="win:UInt32"/>
+        create_descriptor(addr_of!(pid), size_of_val(&pid)),
+    ];
+
+    write_event(unsafe { &SudoDetachedEvent }, &descriptors);
+}
@@ -1,22 +1,36 @@
+mod edit_handler;
 mod elevate_handler;
 mod helpers;
+mod iolog;
 mod logging_bindings;
 mod messages;
 mod r;
+mod relay;
+mod replay_handler;
 mod rpc_bindings;
 mod rpc_bindings_client;
 mod rpc_bindings_server;
 mod run_handler;
 mod tests;
+mod tokens;
 mod tracing;
+mod usage;
+mod write_handler;
 
 use clap::{Arg, ArgAction, ArgMatches, Command};
+use edit_handler::{edit_files, read_file_to_stdout};
 use elevate_handler::start_rpc_server;
 use helpers::*;
-use run_handler::run_target;
+use iolog::parse_playback_speed;
+use messages::JobLimits;
+use replay_handler::replay_recording;
+use run_handler::{run_batch, run_target, BatchCommand, RunOptions};
 use std::env;
+use std::io::BufRead;
 use tracing::*;
+use usage::UsageFormat;
 use windows::{core::*, Win32::Foundation::*, Win32::System::Console::*};
+use write_handler::{write_target, WriteEncoding, WriteOptions};
 
 // Clap does provide a nice macro for args, which defines args with a syntax
 // close to what the actual help text would be. Unfortunately, we're not using
@@ -76,6 +90,10 @@ fn sudo_cli(allowed_mode: i32) -> Command {
                 )
                 // .arg(arg!(-n <NONCE>).required(true))
                 .arg(Arg::new("NONCE").short('n').required(true))
+                // .arg(arg!(--batch))
+                .arg(Arg::new("BATCH").long("batch").action(ArgAction::SetTrue))
+                // .arg(arg!(--request <HASH>))
+                .arg(Arg::new("REQUEST").long("request").action(ArgAction::Set))
                 // .arg(arg!([COMMANDLINE] ... "")),
                 .arg(
                     Arg::new("COMMANDLINE")
@@ -101,7 +119,7 @@ fn sudo_cli(allowed_mode: i32) -> Command {
                 .long("version")
                 .help(r::IDS_BASE_VERSION_HELP.get()),
         );
-    let config = Command::new("config").about(r::IDS_CONFIG_ABOUT.get()).arg(
+    let mut config = Command::new("config").about(r::IDS_CONFIG_ABOUT.get()).arg(
         Arg::new("enable")
             .long("enable")
             .value_parser([
@@ -116,7 +134,77 @@ fn sudo_cli(allowed_mode: i32) -> Command {
             .required(false)
             .action(ArgAction::Set),
     );
+    if cfg!(feature = "Feature_wt") {
+        config = config
+            // arg!(--"wt"[=<PROFILE>]  "Open new windows in Windows Terminal, with PROFILE if given.")
+            .arg(
+                Arg::new("windowsTerminal")
+                    .long("wt")
+                    .value_name("PROFILE")
+                    .help(r::IDS_CONFIG_WT_HELP.get())
+                    .num_args(0..=1)
+                    .require_equals(true)
+                    .default_missing_value("")
+                    .action(ArgAction::Set),
+            )
+            // arg!(--"no-wt"  "Open new windows in conhost again.")
+            .arg(
+                Arg::new("noWindowsTerminal")
+                    .long("no-wt")
+                    .help(r::IDS_CONFIG_NOWT_HELP.get())
+                    .action(ArgAction::SetTrue)
+                    .conflicts_with("windowsTerminal"),
+            );
+    }
     app = app.subcommand(config);
+    app = app
+        .subcommand(write_builder("write", r::IDS_WRITE_ABOUT.get()))
+        .subcommand(write_builder("tee", r::IDS_TEE_ABOUT.get()))
+        .subcommand(
+            Command::new("edit")
+                .about(r::IDS_EDIT_ABOUT.get())
+                .arg_required_else_help(true)
+                // arg!(<FILE> ... "The files to edit")
+                .arg(
+                    Arg::new("FILE")
+                        .help(r::IDS_EDIT_FILE_HELP.get())
+                        .action(ArgAction::Append)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            // `sudo edit` uses this from the elevated side to read the files
+            // it's editing. Like elevate, it's hidden.
+            Command::new("read")
+                .hide(true)
+                .disable_help_flag(true)
+                // .arg(arg!(<FILE>))
+                .arg(Arg::new("FILE").required(true)),
+        );
+    if cfg!(feature = "Feature_iolog") {
+        app = app.subcommand(
+            Command::new("replay")
+                .about(r::IDS_REPLAY_ABOUT.get())
+                .arg_required_else_help(true)
+                // arg!(--speed <SPEED>  "Play the session back this many times as fast as it happened")
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .help(r::IDS_REPLAY_SPEED_HELP.get())
+                        .value_parser(|value: &str| {
+                            parse_playback_speed(value).ok_or(r::IDS_REPLAY_INVALIDSPEED.get())
+                        })
+                        .default_value("1")
+                        .action(ArgAction::Set),
+                )
+                // arg!(<ID>  "The RequestID of the session, from the event log")
+                .arg(
+                    Arg::new("ID")
+                        .help(r::IDS_REPLAY_ID_HELP.get())
+                        .required(true),
+                ),
+        );
+    }
 
     app
 }
@@ -128,11 +216,42 @@ fn run_builder() -> Command {
         .args(run_args())
 }
 
+/// `sudo write` and `sudo tee` take the same args. The only difference is that
+/// `tee` also copies its input to stdout.
+fn write_builder(name: &'static str, about: &'static str) -> Command {
+    Command::new(name)
+        .about(about)
+        .arg_required_else_help(true)
+        // arg!(-a --append  "Append to the file, instead of overwriting it")
+        .arg(
+            Arg::new("append")
+                .short('a')
+                .long("append")
+                .help(r::IDS_WRITE_APPEND_HELP.get())
+                .action(ArgAction::SetTrue),
+        )
+        // arg!(--encoding <ENCODING>  "The encoding to write the file in")
+        .arg(
+            Arg::new("encoding")
+                .long("encoding")
+                .help(r::IDS_WRITE_ENCODING_HELP.get())
+                .value_parser(WriteEncoding::NAMES)
+                .default_value(WriteEncoding::Utf8.name())
+                .action(ArgAction::Set),
+        )
+        // arg!(<FILE>  "The file to write to")
+        .arg(
+            Arg::new("FILE")
+                .help(r::IDS_WRITE_FILE_HELP.get())
+                .required(true),
+        )
+}
+
 fn run_args() -> Vec<clap::Arg> {
     // trailing_var_arg and allow_hyphen_values are needed to allow passing in a
     // command like `sudo netstat -ab` to work as expected, instead of having
     // the parser attempt to treat the `-ab` as args to sudo itself.
-    let args = vec![
+    let mut args = vec![
         // arg!(-E --"preserve-env"  "pass the current environment variables to the command")
         Arg::new("copyEnv")
             .short('E')
@@ -164,6 +283,130 @@ fn run_args() -> Vec<clap::Arg> {
             .long("chdir")
             .help(r::IDS_RUN_CHDIR_HELP.get())
             .action(ArgAction::Set),
+        // arg!(--"system"  "Run the command as NT AUTHORITY\SYSTEM.")
+        Arg::new("system")
+            .long("system")
+            .help(r::IDS_RUN_SYSTEM_HELP.get())
+            .action(ArgAction::SetTrue),
+        // arg!(--"trusted-installer"  "Run the command as NT SERVICE\TrustedInstaller.")
+        Arg::new("trustedInstaller")
+            .long("trusted-installer")
+            .help(r::IDS_RUN_TRUSTEDINSTALLER_HELP.get())
+            .action(ArgAction::SetTrue)
+            .conflicts_with("system"),
+        // arg!(--"drop"  "Run the command unelevated, as the current user.")
+        Arg::new("drop")
+            .long("drop")
+            .help(r::IDS_RUN_DROP_HELP.get())
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["system", "trustedInstaller"]),
+        // arg!(--"privilege"=<NAME>...  "Only give the command this privilege. Can be repeated.")
+        Arg::new("privilege")
+            .long("privilege")
+            .value_name("NAME")
+            .help(r::IDS_RUN_PRIVILEGE_HELP.get())
+            .action(ArgAction::Append),
+        // arg!(--"drop-privilege"=<NAME>...  "Take this privilege away from the command. Can be repeated.")
+        Arg::new("dropPrivilege")
+            .long("drop-privilege")
+            .value_name("NAME")
+            .help(r::IDS_RUN_DROPPRIVILEGE_HELP.get())
+            .action(ArgAction::Append),
+        // arg!(--"no-kill-on-exit"  "Let the command keep running if sudo exits first.")
+        Arg::new("noKillOnExit")
+            .long("no-kill-on-exit")
+            .help(r::IDS_RUN_NOKILLONEXIT_HELP.get())
+            .action(ArgAction::SetTrue),
+        // arg!(--"memory-limit"=<SIZE>  "Limit the memory the command (and its children) can use, like 512M or 2G.")
+        Arg::new("memoryLimit")
+            .long("memory-limit")
+            .value_name("SIZE")
+            .help(r::IDS_RUN_MEMORYLIMIT_HELP.get())
+            .value_parser(|value: &str| {
+                parse_memory_size(value).ok_or(r::IDS_RUN_INVALIDMEMORYLIMIT.get())
+            })
+            .action(ArgAction::Set),
+        // arg!(--"cpu-rate"=<PERCENT>  "Limit the command (and its children) to this percentage of the CPU.")
+        Arg::new("cpuRate")
+            .long("cpu-rate")
+            .value_name("PERCENT")
+            .help(r::IDS_RUN_CPURATE_HELP.get())
+            .value_parser(|value: &str| {
+                parse_cpu_rate(value).ok_or(r::IDS_RUN_INVALIDCPURATE.get())
+            })
+            .action(ArgAction::Set),
+        // arg!(--"max-processes"=<COUNT>  "Limit the number of processes the command can have running at once.")
+        Arg::new("maxProcesses")
+            .long("max-processes")
+            .value_name("COUNT")
+            .help(r::IDS_RUN_MAXPROCESSES_HELP.get())
+            .value_parser(clap::value_parser!(u32).range(1..))
+            .action(ArgAction::Set),
+        // arg!(--"priority"=<PRIORITY>  "Run the command at this priority.")
+        Arg::new("priority")
+            .long("priority")
+            .help(r::IDS_RUN_PRIORITY_HELP.get())
+            .value_parser(PRIORITY_NAMES)
+            .action(ArgAction::Set),
+        // arg!(--"affinity"=<CPUS>  "Only run the command on these CPUs, like 0,2-3 or 0x0d.")
+        Arg::new("affinity")
+            .long("affinity")
+            .value_name("CPUS")
+            .help(r::IDS_RUN_AFFINITY_HELP.get())
+            .value_parser(|value: &str| {
+                parse_affinity(value).ok_or(r::IDS_RUN_INVALIDAFFINITY.get())
+            })
+            .action(ArgAction::Set),
+        // arg!(--"timeout"=<DURATION>  "Stop the command if it's still running after this long, like 30s or 5m.")
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("DURATION")
+            .help(r::IDS_RUN_TIMEOUT_HELP.get())
+            .value_parser(|value: &str| {
+                parse_duration(value).ok_or(r::IDS_RUN_INVALIDTIMEOUT.get())
+            })
+            .action(ArgAction::Set),
+        // arg!(--"time"[=FORMAT]  "Print how long the command took, and what it used, once it exits.")
+        Arg::new("time")
+            .long("time")
+            .value_name("FORMAT")
+            .help(r::IDS_RUN_TIME_HELP.get())
+            .value_parser(UsageFormat::NAMES)
+            .num_args(0..=1)
+            .require_equals(true)
+            .default_missing_value("text")
+            .action(ArgAction::Set),
+        // arg!(--"wait"  "In a new window, wait for the command to exit, and exit with its exit code.")
+        Arg::new("wait")
+            .long("wait")
+            .help(r::IDS_RUN_WAIT_HELP.get())
+            .action(ArgAction::SetTrue),
+        // arg!(-b --"background"  "Start the command in the background, without a console, and print its PID.")
+        Arg::new("background")
+            .short('b')
+            .long("background")
+            .help(r::IDS_RUN_BACKGROUND_HELP.get())
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["wait", "timeout", "time"]),
+        // arg!(--"pid-file"=<FILE>  "With -b, write the command's PID to FILE instead of printing it.")
+        Arg::new("pidFile")
+            .long("pid-file")
+            .value_name("FILE")
+            .help(r::IDS_RUN_PIDFILE_HELP.get())
+            .action(ArgAction::Set)
+            .requires("background"),
+        // arg!(--"batch"=<FILE>  "Run each command in FILE, with a single elevation.")
+        Arg::new("batch")
+            .long("batch")
+            .help(r::IDS_RUN_BATCH_HELP.get())
+            .action(ArgAction::Set)
+            .conflicts_with("COMMANDLINE"),
+        // arg!(--"stop-on-error"  "Don't run the rest of the batch once a command fails.")
+        Arg::new("stopOnError")
+            .long("stop-on-error")
+            .help(r::IDS_RUN_STOPONERROR_HELP.get())
+            .action(ArgAction::SetTrue)
+            .requires("batch"),
         // arg!([COMMANDLINE] ... "Command-line to run")
         Arg::new("COMMANDLINE")
             .help(r::IDS_RUN_COMMANDLINE_HELP.get())
@@ -182,6 +425,32 @@ fn run_args() -> Vec<clap::Arg> {
     //         .help(r::IDS_RUN_SETHOME_HELP.get())
     //         .action(ArgAction::SetTrue)]);
     // }
+
+    if cfg!(feature = "Feature_wt") {
+        // arg!(--"wt"[=<PROFILE>]  "Use a new Windows Terminal tab for the command, with PROFILE if given.")
+        args.push(
+            Arg::new("windowsTerminal")
+                .long("wt")
+                .value_name("PROFILE")
+                .help(r::IDS_RUN_WT_HELP.get())
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("")
+                .action(ArgAction::Set)
+                .conflicts_with_all(["disableInput", "inline", "background"]),
+        );
+    }
+
+    if cfg!(feature = "Feature_conpty") {
+        // arg!(--"pty"  "Run the command in a pseudoconsole, instead of sharing this console with it.")
+        args.push(
+            Arg::new("pty")
+                .long("pty")
+                .help(r::IDS_RUN_PTY_HELP.get())
+                .action(ArgAction::SetTrue)
+                .conflicts_with("background"),
+        );
+    }
     args
 }
 
@@ -290,12 +559,23 @@ fn main() {
 
     trace_log_message(&format!("raw commandline: {:?}", env::args_os()));
     let mode_for_help = allowed_mode_for_help();
-    let matches = sudo_cli(mode_for_help).get_matches();
+    // If we were started as something other than sudo (like sudoedit), this
+    // will turn that into the right subcommand.
+    let args = args_for_exe_name(env::args_os().collect());
+    let matches = sudo_cli(mode_for_help).get_matches_from(args);
 
     let res = match matches.subcommand() {
         Some(("elevate", sub_matches)) => do_elevate(sub_matches),
         Some(("run", sub_matches)) => do_run(sub_matches),
         Some(("config", sub_matches)) => do_config(sub_matches),
+        Some(("write", sub_matches)) => do_write(sub_matches, false),
+        Some(("tee", sub_matches)) => do_write(sub_matches, true),
+        Some(("edit", sub_matches)) => do_edit(sub_matches),
+        Some(("replay", sub_matches)) => do_replay(sub_matches),
+        Some(("read", sub_matches)) => {
+            let file = sub_matches.get_one::<String>("FILE").unwrap();
+            read_file_to_stdout(std::path::Path::new(file))
+        }
         _ => do_run(&matches),
     };
 
@@ -310,6 +590,12 @@ fn main() {
             E_CANCELLED => {
                 eprintln!("{}", r::IDS_CANCELLED.get());
             }
+            E_NO_UNELEVATED_TOKEN => {
+                eprintln!("{}", r::IDS_RUNAS_NOUNELEVATEDTOKEN.get());
+            }
+            E_RECORDING_REQUIRED => {
+                eprintln!("{}", r::IDS_RECORDINGREQUIRED.get());
+            }
             _ if hr == HRESULT::from_win32(ERROR_REQUEST_REFUSED.0) => {
                 eprintln!("{}", r::IDS_SUDO_DISALLOWED.get());
             }
@@ -326,6 +612,10 @@ fn main() {
 }
 
 fn do_run(matches: &ArgMatches) -> Result<i32> {
+    if let Some(batch_file) = matches.get_one::<String>("batch") {
+        return do_batch(batch_file, matches.get_flag("stopOnError"));
+    }
+
     let commandline = matches
         .get_many::<String>("COMMANDLINE")
         .into_iter()
@@ -338,17 +628,42 @@ fn do_run(matches: &ArgMatches) -> Result<i32> {
         // return exit status 1 if the commandline was empty, 0 otherwise
         return Ok(commandline.is_empty().into());
     }
-    let requested_dir: Option<String> = matches.get_one::<String>("chdir").map(|s| s.into());
     let allowed_mode = check_enabled_or_bail();
-    let copy_env = matches.get_flag("copyEnv");
 
-    if !can_current_user_elevate()? {
-        // Bail out with an error. main(0) will then print the error message to
-        // the user to let them know they aren't allowed to run sudo.
-        return Err(ERROR_REQUEST_REFUSED.into());
+    check_can_elevate()?;
+
+    let options = run_options_from_matches(matches, allowed_mode);
+    run_target(&options, &commandline)
+}
+
+/// Checks that the current user can elevate at all. If they can't, this
+/// returns ERROR_REQUEST_REFUSED, and main() will print the error message to
+/// let them know they aren't allowed to run sudo. If UAC is off, nobody can
+/// elevate, so this prints a message saying so and exits.
+fn check_can_elevate() -> Result<()> {
+    match get_current_elevation_state()? {
+        ElevationState::AlreadyElevated
+        | ElevationState::CanElevate
+        | ElevationState::CanElevateOverTheShoulder => Ok(()),
+        ElevationState::Disallowed => Err(ERROR_REQUEST_REFUSED.into()),
+        ElevationState::UacDisabled => {
+            eprintln!("{}", r::IDS_UACDISABLED.get());
+            std::process::exit(-1);
+        }
     }
+}
 
-    let requested_mode = if matches.get_flag("newWindow") {
+/// Builds the options for running a single command out of the args we were
+/// given. If they asked for a mode higher than what's currently allowed, this
+/// will print an error and exit.
+fn run_options_from_matches(matches: &ArgMatches, allowed_mode: SudoMode) -> RunOptions {
+    // `--wt` asks for a new window too, just in Windows Terminal.
+    let requested_wt = if cfg!(feature = "Feature_wt") {
+        matches.get_one::<String>("windowsTerminal").cloned()
+    } else {
+        None
+    };
+    let requested_mode = if matches.get_flag("newWindow") || requested_wt.is_some() {
         Some(SudoMode::ForceNewWindow)
     } else if matches.get_flag("disableInput") {
         Some(SudoMode::DisableInput)
@@ -372,9 +687,226 @@ fn do_run(matches: &ArgMatches) -> Result<i32> {
         }
     }
 
-    let actual_mode = std::cmp::min(allowed_mode, requested_mode.unwrap_or(allowed_mode));
+    let run_as = if matches.get_flag("system") {
+        RunAs::System
+    } else if matches.get_flag("trustedInstaller") {
+        RunAs::TrustedInstaller
+    } else if matches.get_flag("drop") {
+        RunAs::Unelevated
+    } else {
+        RunAs::Default
+    };
+    if let Some(policy) = run_as_policy(run_as) {
+        let config: RegistryConfigProvider = Default::default();
+        if !is_policy_enabled(&config, policy) {
+            let translated_msg = match run_as {
+                RunAs::TrustedInstaller => r::IDS_RUNAS_TRUSTEDINSTALLERDISABLEDBYPOLICY.get(),
+                _ => r::IDS_RUNAS_SYSTEMDISABLEDBYPOLICY.get(),
+            };
+            eprintln!("{}", translated_msg);
+            std::process::exit(-1);
+        }
+    }
+    if run_as == RunAs::TrustedInstaller && !confirm_trusted_installer() {
+        std::process::exit(1);
+    }
+
+    RunOptions {
+        copy_env: matches.get_flag("copyEnv"),
+        sudo_mode: std::cmp::min(allowed_mode, requested_mode.unwrap_or(allowed_mode)),
+        requested_dir: matches.get_one::<String>("chdir").cloned(),
+        run_as,
+        keep_privileges: string_values(matches, "privilege"),
+        drop_privileges: string_values(matches, "dropPrivilege"),
+        kill_on_exit: !matches.get_flag("noKillOnExit"),
+        limits: job_limits_from_matches(matches),
+        timeout: matches.get_one("timeout").copied(),
+        usage_report: matches
+            .get_one::<String>("time")
+            .and_then(|name| UsageFormat::from_name(name)),
+        wait: matches.get_flag("wait"),
+        background: matches.get_flag("background"),
+        pid_file: matches.get_one::<String>("pidFile").cloned(),
+        pseudoconsole: cfg!(feature = "Feature_conpty") && matches.get_flag("pty"),
+        windows_terminal: requested_wt.or_else(|| {
+            let config: RegistryConfigProvider = Default::default();
+            get_windows_terminal_setting(&config)
+        }),
+    }
+}
+
+fn job_limits_from_matches(matches: &ArgMatches) -> JobLimits {
+    let priority_class = matches
+        .get_one::<String>("priority")
+        .and_then(|name| priority_class_from_name(name));
+    JobLimits {
+        memory_limit: matches.get_one("memoryLimit").copied().unwrap_or(0),
+        affinity: matches.get_one("affinity").copied().unwrap_or(0),
+        cpu_rate: matches.get_one("cpuRate").copied().unwrap_or(0),
+        max_processes: matches.get_one("maxProcesses").copied().unwrap_or(0),
+        priority_class: priority_class.map_or(0, |class| class.0),
+    }
+}
+
+/// Collects all the values of a repeatable arg.
+fn string_values(matches: &ArgMatches, id: &str) -> Vec<String> {
+    matches
+        .get_many::<String>(id)
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+}
+
+/// TrustedInstaller can change just about anything on the machine, including
+/// the OS's own files. Make sure they really meant to do this before we go and
+/// start anything.
+///
+/// This reads the answer from the console, rather than stdin, since stdin
+/// might be redirected for the command itself.
+fn confirm_trusted_installer() -> bool {
+    eprint!("{} ", r::IDS_RUNAS_TRUSTEDINSTALLERCONFIRM.get());
+    let mut answer = String::new();
+    let read = std::fs::File::open("CONIN$")
+        .and_then(|console| std::io::BufReader::new(console).read_line(&mut answer));
+    if read.is_err() {
+        // There's no one there to ask. Don't assume they said yes.
+        eprintln!();
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Runs every command in the given batch file (or stdin, for `-`) with a
+/// single elevation. Each line in the file is parsed just like the args to
+/// `sudo run`, so each command can have its own mode, directory, etc.
+fn do_batch(batch_file: &str, stop_on_error: bool) -> Result<i32> {
+    let allowed_mode = check_enabled_or_bail();
+
+    check_can_elevate()?;
+
+    let contents = if batch_file == "-" {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(batch_file)?
+    };
+
+    // Parse the whole file before we run anything, so that a typo on the last
+    // line doesn't leave the machine half set up.
+    let mut commands = Vec::new();
+    for (line_number, args) in parse_batch_file(&contents) {
+        let invalid_line = || {
+            let translated_msg = r::IDS_BATCH_INVALIDLINE.get();
+            eprintln!(
+                "{}",
+                translated_msg.replace("{0}", &line_number.to_string())
+            );
+        };
+
+        let args = std::iter::once("run".to_string()).chain(args);
+        let matches = match run_builder().try_get_matches_from(args) {
+            Ok(matches) => matches,
+            Err(err) => {
+                invalid_line();
+                _ = err.print();
+                std::process::exit(-1);
+            }
+        };
+
+        let commandline = matches
+            .get_many::<String>("COMMANDLINE")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect::<Vec<_>>();
+        // Batches can't be nested.
+        if commandline.is_empty() || matches.get_one::<String>("batch").is_some() {
+            invalid_line();
+            std::process::exit(-1);
+        }
+
+        commands.push(BatchCommand {
+            options: run_options_from_matches(&matches, allowed_mode),
+            commandline,
+        });
+    }
+
+    if commands.is_empty() {
+        return Ok(0);
+    }
 
-    run_target(copy_env, &commandline, actual_mode, requested_dir)
+    let batch_name = if batch_file == "-" {
+        batch_file.to_string()
+    } else {
+        absolute_path(std::path::Path::new(batch_file))?
+            .to_string_lossy()
+            .into_owned()
+    };
+    run_batch(&commands, &batch_name, stop_on_error)
+}
+
+/// Writes our stdin to a file, from the elevated side. This is for things like
+/// `echo foo | sudo write C:\Windows\foo.txt`, where a plain `>` would have
+/// been done by the (unelevated) shell.
+fn do_write(matches: &ArgMatches, tee: bool) -> Result<i32> {
+    check_can_write_files_or_bail()?;
+
+    let file = matches.get_one::<String>("FILE").unwrap();
+    let options = WriteOptions {
+        append: matches.get_flag("append"),
+        tee,
+        encoding: matches
+            .get_one::<String>("encoding")
+            .and_then(|name| WriteEncoding::from_name(name))
+            .unwrap_or(WriteEncoding::Utf8),
+    };
+    write_target(file, &options)
+}
+
+/// Edits protected files with the user's own (unelevated) editor.
+fn do_edit(matches: &ArgMatches) -> Result<i32> {
+    check_can_write_files_or_bail()?;
+
+    let files = matches
+        .get_many::<String>("FILE")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    edit_files(&files)
+}
+
+/// Plays back the recording of an elevated session.
+fn do_replay(matches: &ArgMatches) -> Result<i32> {
+    let allowed_mode = check_enabled_or_bail();
+    check_can_elevate()?;
+    // Unless we're elevated already, reading the recording needs the
+    // elevated sudo to use our std handles, just like `sudo edit`.
+    if allowed_mode < SudoMode::Normal && !is_running_elevated()? {
+        eprintln!("{}", r::IDS_REPLAY_REQUIRESINLINE.get());
+        std::process::exit(-1);
+    }
+
+    let id = matches.get_one::<String>("ID").unwrap();
+    let speed = *matches.get_one::<f64>("speed").unwrap();
+    let mode = enable_vt();
+    let result = replay_recording(id, speed);
+    _ = restore_console_mode(mode);
+    result
+}
+
+/// `sudo write` and `sudo edit` both need the elevated sudo to use our std
+/// handles, so they need to be able to run inline. If we can't, this will
+/// print an error and exit.
+fn check_can_write_files_or_bail() -> Result<()> {
+    let allowed_mode = check_enabled_or_bail();
+
+    check_can_elevate()?;
+
+    if allowed_mode < SudoMode::Normal {
+        eprintln!("{}", r::IDS_WRITE_REQUIRESINLINE.get());
+        std::process::exit(-1);
+    }
+    Ok(())
 }
 
 fn do_elevate(matches: &ArgMatches) -> Result<i32> {
@@ -402,6 +934,8 @@ fn do_elevate(matches: &ArgMatches) -> Result<i32> {
         nonce.ok().unwrap(),
         None,
         &commandline,
+        matches.get_one::<String>("REQUEST"),
+        matches.get_flag("BATCH"),
     );
     trace_log_message(&format!("elevate result: {result:?}"));
     result
@@ -428,6 +962,14 @@ fn do_config(matches: &ArgMatches) -> Result<i32> {
         None => check_enabled_or_bail(),
     };
 
+    if cfg!(feature = "Feature_wt") {
+        if let Some(profile) = matches.get_one::<String>("windowsTerminal") {
+            try_set_windows_terminal(Some(profile.as_str()))?;
+        } else if matches.get_flag("noWindowsTerminal") {
+            try_set_windows_terminal(None)?;
+        }
+    }
+
     match mode {
         SudoMode::Disabled => println!("{}", r::IDS_DISABLEDMESSAGE.get()),
         SudoMode::ForceNewWindow => println!("{}", r::IDS_CURRENTMODE_FORCENEWWINDOW.get()),
@@ -435,9 +977,52 @@ fn do_config(matches: &ArgMatches) -> Result<i32> {
         SudoMode::Normal => println!("{}", r::IDS_CURRENTMODE_INLINE.get()),
     }
 
+    let config: RegistryConfigProvider = Default::default();
+    match get_windows_terminal_setting(&config) {
+        Some(profile) if profile.is_empty() => println!("{}", r::IDS_CURRENTWT_DEFAULT.get()),
+        Some(profile) => println!(
+            "{}",
+            r::IDS_CURRENTWT_PROFILE.get().replace("{0}", &profile)
+        ),
+        None => {}
+    }
+
     Ok(0)
 }
 
+/// Turns opening new windows in Windows Terminal on (with the given profile,
+/// or the default one if it's empty) or off. Like the mode, this is for the
+/// whole machine, so only an admin can change it.
+fn try_set_windows_terminal(profile: Option<&str>) -> Result<()> {
+    if !is_running_elevated()? {
+        eprintln!("{}", r::IDS_REQUIREADMINTOCONFIG.get());
+        std::process::exit(-1);
+    }
+
+    let result = windows_registry::LOCAL_MACHINE
+        .create("SOFTWARE\\Microsoft\\Windows\\CurrentVersion\\Sudo")
+        .and_then(|key| {
+            key.set_u32(SETTING_USE_WINDOWS_TERMINAL, profile.is_some().into())?;
+            match profile {
+                Some(profile) if !profile.is_empty() => {
+                    key.set_string(SETTING_WINDOWS_TERMINAL_PROFILE, profile)
+                }
+                // Leaving the profile in there would be harmless, but stale.
+                _ => match key.remove_value(SETTING_WINDOWS_TERMINAL_PROFILE) {
+                    Err(err) if err.code() == E_FILENOTFOUND => Ok(()),
+                    result => result,
+                },
+            }
+        });
+
+    if let Err(err) = result {
+        eprintln!("{} {}", r::IDS_ERRORSETTINGMODE.get(), err);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
 fn try_enable_sudo(requested_mode: SudoMode) -> Result<()> {
     let elevated = is_running_elevated()?;
     if !elevated {
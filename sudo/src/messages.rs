@@ -1,13 +1,229 @@
-use crate::helpers::SudoMode;
-use windows::{core::GUID, Win32::Foundation::HANDLE};
+use crate::helpers::{pack_coord_for_rpc, RunAs, SudoMode};
+use crate::usage::UsageFormat;
+use std::time::Duration;
+use windows::Win32::Security::Cryptography::{BCryptHash, BCRYPT_SHA256_ALG_HANDLE};
+use windows::{core::*, Win32::Foundation::HANDLE, Win32::System::Console::COORD};
 
+/// The target should be killed if the sudo that asked for it exits first.
+pub const REQUEST_FLAG_KILL_ON_EXIT: u32 = 0x1;
+/// The target should run in the background, without a console (`-b`).
+pub const REQUEST_FLAG_DETACHED: u32 = 0x2;
+/// The client has no console, so the target only gets a hidden one. The
+/// client relays its output instead.
+pub const REQUEST_FLAG_HEADLESS: u32 = 0x4;
+
+/// Limits for the job that the target runs in, and the priority it runs at.
+/// Zero means "no limit" for all of these. This is passed as-is over RPC, so it
+/// needs to match JOB_LIMITS in sudo_rpc.idl.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JobLimits {
+    /// `--memory-limit`, in bytes. This is for the whole job, not per process.
+    pub memory_limit: u64,
+    /// `--affinity`, as a mask of CPUs.
+    pub affinity: u64,
+    /// `--cpu-rate`, in hundredths of a percent.
+    pub cpu_rate: u32,
+    /// `--max-processes`.
+    pub max_processes: u32,
+    /// `--priority`, as one of the *_PRIORITY_CLASS values.
+    pub priority_class: u32,
+}
+
+/// How the client's console is set up, for a target that gets a new window.
+/// Otherwise, its console would start out with the default settings, like the
+/// wrong code page. All zero if there's nothing to carry over. This is passed
+/// as-is over RPC, so it needs to match CONSOLE_STATE in sudo_rpc.idl.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConsoleState {
+    pub input_cp: u32,
+    pub output_cp: u32,
+    /// Nonzero if the client's console has VT processing turned on.
+    pub virtual_terminal: u32,
+    /// The size of the window, and of its buffer, packed with
+    /// pack_coord_for_rpc.
+    pub window_size: u32,
+    pub buffer_size: u32,
+}
+
+impl ConsoleState {
+    pub fn is_empty(&self) -> bool {
+        *self == ConsoleState::default()
+    }
+}
+
+impl JobLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == JobLimits::default()
+    }
+
+    /// True if any of these can only be enforced with a job. The priority
+    /// class can be set on the target directly.
+    pub fn needs_job(&self) -> bool {
+        JobLimits {
+            priority_class: 0,
+            ..*self
+        } != JobLimits::default()
+    }
+}
+
+#[derive(Clone)]
 pub struct ElevateRequest {
     pub parent_pid: u32,
     pub handles: [HANDLE; 3], // in, out, err
     pub sudo_mode: SudoMode,
+    pub run_as: RunAs,
+    pub kill_on_exit: bool,
+    /// How long the client will wait for the target before killing it.
+    pub timeout: Option<Duration>,
+    /// If set, the client prints a `--time` report once the target exits.
+    /// This isn't sent over RPC, since it's only the client's business.
+    pub usage_report: Option<UsageFormat>,
+    /// In new window mode, the client normally doesn't wait for the target.
+    /// This makes it wait anyway. Also not sent over RPC.
+    pub wait: bool,
+    /// `-b`. The target doesn't get the client's console, and the client
+    /// doesn't wait for it.
+    pub detached: bool,
+    /// Where the client writes the PID of a detached target. Only the client
+    /// cares about this.
+    pub pid_file: Option<String>,
+    /// The client has no console for the target to share (it was started by
+    /// a GUI, or a scheduled task, say). The target gets pipes to the client
+    /// for its output instead, which the client copies to its own std handles.
+    pub headless: bool,
+    /// If set, the target gets a pseudoconsole this size, instead of sharing
+    /// the client's console. `handles` are then the pseudoconsole's input and
+    /// output, which the client relays to its console.
+    pub pseudoconsole: Option<COORD>,
+    /// Whatever the target would print to the client's console comes through
+    /// pipes to the client instead, which copies it to its console. That way,
+    /// the elevated sudo can record it. Only the client cares about this.
+    pub relay_output: bool,
     pub application: String,
     pub args: Vec<String>,
     pub target_dir: String,
     pub env_vars: String,
+    /// If this isn't empty, the target only gets these privileges.
+    pub keep_privileges: Vec<String>,
+    /// Privileges to take away from the target.
+    pub drop_privileges: Vec<String>,
+    pub limits: JobLimits,
+    /// For a new window, how to set up its console, so that it matches the
+    /// client's. The elevated sudo makes the console itself if this isn't
+    /// empty, so only send it in a request over RPC.
+    pub console: ConsoleState,
+    /// The client's console title, for the new window. Empty to leave it be.
+    pub console_title: String,
     pub event_id: GUID,
+    pub batch_id: GUID, // zeroed if this request isn't part of a batch
+}
+
+impl ElevateRequest {
+    /// The REQUEST_FLAG_* bits for this request, for sending over RPC.
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.kill_on_exit {
+            flags |= REQUEST_FLAG_KILL_ON_EXIT;
+        }
+        if self.detached {
+            flags |= REQUEST_FLAG_DETACHED;
+        }
+        if self.headless {
+            flags |= REQUEST_FLAG_HEADLESS;
+        }
+        flags
+    }
+
+    /// The timeout, for sending over RPC. Zero if there isn't one. It stops
+    /// short of u32::MAX, which is INFINITE to anything that waits on it.
+    pub fn timeout_ms(&self) -> u32 {
+        self.timeout.map_or(0, |timeout| {
+            timeout.as_millis().min(u32::MAX as u128 - 1) as u32
+        })
+    }
+
+    /// A SHA-256 of everything in the request that the elevated sudo acts on,
+    /// as hex. The client puts this on the elevated sudo's commandline, which
+    /// is what gets approved in the UAC dialog. See ApprovedRequest.
+    pub fn approval_hash(&self) -> Result<String> {
+        let mut hash = [0u8; 32];
+        unsafe {
+            BCryptHash(
+                BCRYPT_SHA256_ALG_HANDLE,
+                None,
+                &self.approval_bytes(),
+                &mut hash,
+            )
+        }
+        .ok()?;
+        Ok(hash.iter().map(|byte| format!("{byte:02x}")).collect())
+    }
+
+    /// The parts of the request that go into approval_hash. The strings are
+    /// length-prefixed, so that moving text from one to the next changes the
+    /// hash too. The handles aren't in here, since the client can swap those
+    /// for pipes to a relay after the fact.
+    fn approval_bytes(&self) -> Vec<u8> {
+        fn push_str(bytes: &mut Vec<u8>, s: &str) {
+            bytes.extend((s.len() as u64).to_le_bytes());
+            bytes.extend(s.as_bytes());
+        }
+        fn push_list(bytes: &mut Vec<u8>, list: &[String]) {
+            bytes.extend((list.len() as u64).to_le_bytes());
+            list.iter().for_each(|s| push_str(bytes, s));
+        }
+
+        let mut bytes = Vec::new();
+        for value in [
+            self.sudo_mode as u32,
+            self.run_as as u32,
+            self.flags(),
+            self.timeout_ms(),
+            pack_coord_for_rpc(self.pseudoconsole),
+            self.limits.cpu_rate,
+            self.limits.max_processes,
+            self.limits.priority_class,
+            self.console.input_cp,
+            self.console.output_cp,
+            self.console.virtual_terminal,
+            self.console.window_size,
+            self.console.buffer_size,
+        ] {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.extend(self.limits.memory_limit.to_le_bytes());
+        bytes.extend(self.limits.affinity.to_le_bytes());
+        bytes.extend(self.event_id.to_u128().to_le_bytes());
+        bytes.extend(self.batch_id.to_u128().to_le_bytes());
+        push_str(&mut bytes, &self.application);
+        push_list(&mut bytes, &self.args);
+        push_str(&mut bytes, &self.target_dir);
+        push_str(&mut bytes, &self.env_vars);
+        push_list(&mut bytes, &self.keep_privileges);
+        push_list(&mut bytes, &self.drop_privileges);
+        push_str(&mut bytes, &self.console_title);
+        bytes
+    }
+}
+
+/// The request that was approved in the UAC dialog, from the elevated sudo's
+/// commandline: the target's commandline, which the admin could read there,
+/// and the approval_hash of the whole request.
+///
+/// The elevated sudo only runs a request that matches. Otherwise, once it's
+/// been approved, the client could send it anything. With over-the-shoulder
+/// elevation, the client isn't even the admin who approved it.
+pub struct ApprovedRequest {
+    pub hash: String,
+    /// The target's application, followed by its args.
+    pub commandline: Vec<String>,
+}
+
+impl ApprovedRequest {
+    pub fn matches(&self, request: &ElevateRequest) -> Result<bool> {
+        let commandline = std::iter::once(&request.application).chain(&request.args);
+        Ok(commandline.eq(&self.commandline) && request.approval_hash()? == self.hash)
+    }
 }
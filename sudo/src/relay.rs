@@ -0,0 +1,407 @@
+use crate::rpc_bindings_client::can_send_over_rpc;
+use std::os::windows::io::AsRawHandle;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use windows::{
+    core::*, Win32::Foundation::*, Win32::Globalization::CP_UTF8, Win32::Storage::FileSystem::*,
+    Win32::System::Console::*, Win32::System::Pipes::*, Win32::System::IO::*,
+};
+
+/// Relays a target's console I/O to and from our console, for targets that
+/// don't get to use our console themselves. There are three of those:
+///
+/// * With `--pty`, the elevated sudo gives the target a pseudoconsole, out of
+///   the pipes we hand it. Everything the target prints comes back to us as
+///   VT, which we write to our console, and the keys they type get sent back
+///   to it as VT too.
+/// * In DisableInput mode, the target gets a hidden console of its own, so
+///   there's no way for it to read what they type. Its output comes back to
+///   us over pipes, and we copy that to our console.
+/// * If we don't have a console ourselves, the target only gets a hidden one.
+///   Its output comes back to us over pipes, and we copy that to wherever our
+///   std handles go.
+///
+/// On top of either of those, or on its own, it can relay the handles that we
+/// can't send to the elevated sudo over RPC, like NUL, serial ports or
+/// sockets. The target gets a pipe instead, and we copy between that and
+/// wherever they redirected it to.
+///
+/// Dropping the relay waits for the last of the target's output, so don't drop
+/// it until the target's exited.
+pub struct Relay {
+    /// The handles to send to the elevated sudo, in place of our std handles.
+    remote_handles: [HANDLE; 3],
+    /// The pipe ends behind remote_handles. We only need these until they've
+    /// been sent to the elevated sudo.
+    remote: Vec<Owned<HANDLE>>,
+    inputs: Vec<JoinHandle<()>>,
+    outputs: Vec<JoinHandle<()>>,
+    /// How our console was set up before we started, if we had to change it.
+    saved_console: Option<SavedConsole>,
+}
+
+struct SavedConsole {
+    console_in: HANDLE,
+    console_out: HANDLE,
+    input_mode: CONSOLE_MODE,
+    output_mode: CONSOLE_MODE,
+    output_cp: u32,
+}
+
+/// The size the pseudoconsole should be, to fit our console window. None if
+/// we don't have a console for it to stand in for.
+pub fn console_size() -> Option<COORD> {
+    unsafe {
+        let console_out = GetStdHandle(STD_OUTPUT_HANDLE).ok()?;
+        let mut info = CONSOLE_SCREEN_BUFFER_INFO::default();
+        GetConsoleScreenBufferInfo(console_out, &mut info).ok()?;
+        Some(COORD {
+            X: info.srWindow.Right - info.srWindow.Left + 1,
+            Y: info.srWindow.Bottom - info.srWindow.Top + 1,
+        })
+    }
+}
+
+fn create_pipe() -> Result<(Owned<HANDLE>, Owned<HANDLE>)> {
+    unsafe {
+        let mut read = Owned::default();
+        let mut write = Owned::default();
+        CreatePipe(&mut *read, &mut *write, None, 0)?;
+        Ok((read, write))
+    }
+}
+
+impl Relay {
+    fn new() -> Relay {
+        Relay {
+            remote_handles: Default::default(),
+            remote: Vec::new(),
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            saved_console: None,
+        }
+    }
+
+    /// Starts relaying a pseudoconsole. This puts our console into VT mode
+    /// until the relay is dropped.
+    pub fn pseudoconsole() -> Result<Relay> {
+        let (input_read, input_write) = create_pipe()?;
+        let (output_read, output_write) = create_pipe()?;
+
+        let mut relay = Relay::new();
+        // The pseudoconsole reads its input from the first, and writes its
+        // output to the second.
+        relay.remote_handles = [*input_read, *output_write, HANDLE::default()];
+        relay.remote = vec![input_read, output_write];
+
+        let saved = unsafe {
+            let console_in = GetStdHandle(STD_INPUT_HANDLE)?;
+            let console_out = GetStdHandle(STD_OUTPUT_HANDLE)?;
+            let mut input_mode = CONSOLE_MODE::default();
+            let mut output_mode = CONSOLE_MODE::default();
+            GetConsoleMode(console_in, &mut input_mode)?;
+            GetConsoleMode(console_out, &mut output_mode)?;
+            SavedConsole {
+                console_in,
+                console_out,
+                input_mode,
+                output_mode,
+                output_cp: GetConsoleOutputCP(),
+            }
+        };
+        let (console_in, console_out) = (saved.console_in, saved.console_out);
+        let (input_mode, output_mode) = (saved.input_mode, saved.output_mode);
+        relay.saved_console = Some(saved);
+
+        unsafe {
+            // The pseudoconsole sends us VT, in UTF-8. Let our console deal
+            // with both, rather than trying to translate it ourselves.
+            SetConsoleOutputCP(CP_UTF8)?;
+            SetConsoleMode(
+                console_out,
+                output_mode | ENABLE_VIRTUAL_TERMINAL_PROCESSING | DISABLE_NEWLINE_AUTO_RETURN,
+            )?;
+            // Every key goes to the target as-is, including Ctrl+C. It's the
+            // pseudoconsole's job to turn that into a Ctrl+C event for the
+            // target, not ours.
+            SetConsoleMode(
+                console_in,
+                (input_mode & !(ENABLE_LINE_INPUT | ENABLE_ECHO_INPUT | ENABLE_PROCESSED_INPUT))
+                    | ENABLE_VIRTUAL_TERMINAL_INPUT,
+            )?;
+        }
+
+        relay.outputs.push(std::thread::spawn(move || {
+            relay_file(*output_read, console_out)
+        }));
+        relay.inputs.push(std::thread::spawn(move || {
+            relay_input(console_in, input_write)
+        }));
+        Ok(relay)
+    }
+
+    /// Starts relaying the output of a target that isn't on our console.
+    /// `handles` are the ones we'd otherwise send the elevated sudo - the
+    /// invalid ones are on our console, so those are the ones that need
+    /// relaying. The target gets no input from us at all.
+    pub fn output_only(handles: [HANDLE; 3]) -> Result<Relay> {
+        let mut relay = Relay::new();
+        relay.remote_handles = handles;
+        for (index, std_handle) in [(1, STD_OUTPUT_HANDLE), (2, STD_ERROR_HANDLE)] {
+            if !handles[index].is_invalid() {
+                continue;
+            }
+            let (read, write) = create_pipe()?;
+            relay.remote_handles[index] = *write;
+            relay.remote.push(write);
+            let console = unsafe { GetStdHandle(std_handle)? };
+            relay
+                .outputs
+                .push(std::thread::spawn(move || relay_file(*read, console)));
+        }
+        Ok(relay)
+    }
+
+    /// Starts relaying the output of a target that isn't on a console anyone
+    /// can see, because we don't have one. Everything it writes comes back to
+    /// us over pipes, and we copy that to our own std handles (`handles`). If
+    /// we don't have one of those, neither does the target. Its stdin it gets
+    /// as-is.
+    pub fn headless(handles: [HANDLE; 3]) -> Result<Relay> {
+        let mut relay = Relay::new();
+        relay.remote_handles = handles;
+        for index in [1, 2] {
+            let ours = handles[index];
+            if ours.is_invalid() {
+                continue;
+            }
+            let (read, write) = create_pipe()?;
+            relay.remote_handles[index] = *write;
+            relay.remote.push(write);
+            relay
+                .outputs
+                .push(std::thread::spawn(move || relay_file(*read, ours)));
+        }
+        Ok(relay)
+    }
+
+    /// A relay that sends `handles` as they are. It won't do anything, unless
+    /// we also relay_unsendable_handles.
+    pub fn passthrough(handles: [HANDLE; 3]) -> Relay {
+        let mut relay = Relay::new();
+        relay.remote_handles = handles;
+        relay
+    }
+
+    /// Swaps each of our remote handles that can't be sent over RPC for a
+    /// pipe, and relays between the two. This needs us around until the
+    /// target's done with them, so only do it if we're waiting for it.
+    pub fn relay_unsendable_handles(&mut self) -> Result<()> {
+        for index in 0..3 {
+            let handle = self.remote_handles[index];
+            if handle.is_invalid() || can_send_over_rpc(handle) {
+                continue;
+            }
+            let (read, write) = create_pipe()?;
+            if index == 0 {
+                self.remote_handles[index] = *read;
+                self.remote.push(read);
+                self.inputs.push(std::thread::spawn(move || {
+                    relay_file(handle, *write);
+                    drop(write)
+                }));
+            } else {
+                self.remote_handles[index] = *write;
+                self.remote.push(write);
+                self.outputs.push(std::thread::spawn(move || {
+                    relay_file(*read, handle);
+                    drop(read)
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// The handles to send to the elevated sudo, in place of our std handles.
+    pub fn remote_handles(&self) -> [HANDLE; 3] {
+        self.remote_handles
+    }
+
+    /// Closes our copies of the pipe ends we sent to the elevated sudo. Once
+    /// it's done with them, our end of the output pipes break, and that's how
+    /// we know the target's output is done.
+    pub fn close_remote_handles(&mut self) {
+        self.remote.clear();
+        self.remote_handles = Default::default();
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        self.close_remote_handles();
+        for output in self.outputs.drain(..) {
+            _ = output.join();
+        }
+        for input in self.inputs.drain(..) {
+            // The input thread is almost certainly stuck waiting for a key, or
+            // for a device with nothing to say. Keep poking it until it
+            // notices it's been cancelled - it might not have gotten to
+            // reading yet the first time.
+            while !input.is_finished() {
+                _ = unsafe { CancelSynchronousIo(HANDLE(input.as_raw_handle() as _)) };
+                std::thread::sleep(Duration::from_millis(10));
+            }
+            _ = input.join();
+        }
+        if let Some(saved) = self.saved_console.take() {
+            unsafe {
+                _ = SetConsoleMode(saved.console_in, saved.input_mode);
+                _ = SetConsoleMode(saved.console_out, saved.output_mode);
+                _ = SetConsoleOutputCP(saved.output_cp);
+            }
+        }
+    }
+}
+
+/// Copies everything from one handle to the other, as-is, until either end is
+/// done. Mostly that's the target's output, on its way to our console.
+fn relay_file(from: HANDLE, to: HANDLE) {
+    let mut buffer = [0u8; 4096];
+    loop {
+        let mut read = 0;
+        let result = unsafe { ReadFile(from, Some(&mut buffer), Some(&mut read), None) };
+        if result.is_err() || read == 0 {
+            break;
+        }
+        let data = &buffer[..read as usize];
+        if unsafe { WriteFile(to, Some(data), None, None) }.is_err() {
+            break;
+        }
+    }
+}
+
+/// Copies the keys they type to the pseudoconsole, until we're cancelled, or
+/// it goes away.
+fn relay_input(from: HANDLE, to: Owned<HANDLE>) {
+    let mut buffer = [0u16; 1024];
+    let mut high_surrogate = None;
+    loop {
+        let mut read = 0;
+        let result = unsafe {
+            ReadConsoleW(
+                from,
+                buffer.as_mut_ptr() as _,
+                buffer.len() as u32,
+                &mut read,
+                None,
+            )
+        };
+        if result.is_err() || read == 0 {
+            break;
+        }
+        let data = utf16_to_utf8(&buffer[..read as usize], &mut high_surrogate);
+        if unsafe { WriteFile(*to, Some(&data), None, None) }.is_err() {
+            break;
+        }
+    }
+}
+
+/// Converts console input to the UTF-8 the pseudoconsole wants. A character
+/// outside the BMP can get split between two reads, so if `units` ends halfway
+/// through one, that half gets saved in `high_surrogate` for next time.
+fn utf16_to_utf8(units: &[u16], high_surrogate: &mut Option<u16>) -> Vec<u8> {
+    let mut units: Vec<u16> = high_surrogate
+        .take()
+        .into_iter()
+        .chain(units.iter().copied())
+        .collect();
+    if let Some(&last) = units.last() {
+        if (0xD800..0xDC00).contains(&last) {
+            *high_surrogate = units.pop();
+        }
+    }
+    String::from_utf16_lossy(&units).into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_utf16_to_utf8() {
+        let mut high_surrogate = None;
+        let ascii: Vec<u16> = "dir\r".encode_utf16().collect();
+        assert_eq!(utf16_to_utf8(&ascii, &mut high_surrogate), b"dir\r");
+        assert_eq!(high_surrogate, None);
+
+        // 😀 is U+1F600, which is D83D DE00 in UTF-16.
+        assert_eq!(utf16_to_utf8(&[0x61, 0xD83D], &mut high_surrogate), b"a");
+        assert_eq!(high_surrogate, Some(0xD83D));
+        assert_eq!(
+            utf16_to_utf8(&[0xDE00, 0x62], &mut high_surrogate),
+            "😀b".as_bytes()
+        );
+        assert_eq!(high_surrogate, None);
+
+        // A low surrogate on its own is still garbage.
+        assert_eq!(
+            utf16_to_utf8(&[0xDE00], &mut high_surrogate),
+            "\u{FFFD}".as_bytes()
+        );
+    }
+
+    #[test]
+    fn test_relay_unsendable_handles() {
+        let nul = unsafe {
+            Owned::new(
+                CreateFileW(
+                    w!("NUL"),
+                    FILE_GENERIC_WRITE.0,
+                    FILE_SHARE_READ | FILE_SHARE_WRITE,
+                    None,
+                    OPEN_EXISTING,
+                    FILE_ATTRIBUTE_NORMAL,
+                    None,
+                )
+                .unwrap(),
+            )
+        };
+        let (_pipe_read, pipe_write) = create_pipe().unwrap();
+        assert!(!can_send_over_rpc(*nul));
+        assert!(can_send_over_rpc(*pipe_write));
+
+        let mut relay = Relay::passthrough([HANDLE::default(), *nul, *pipe_write]);
+        relay.relay_unsendable_handles().unwrap();
+        let remote = relay.remote_handles();
+        // NUL gets swapped for a pipe, the pipe that was already there doesn't.
+        assert!(remote[0].is_invalid());
+        assert_ne!(remote[1], *nul);
+        assert!(can_send_over_rpc(remote[1]));
+        assert_eq!(remote[2], *pipe_write);
+
+        unsafe { WriteFile(remote[1], Some(b"hello"), None, None) }.unwrap();
+        // Once the remote ends are closed, the relay is done, so this can't
+        // hang.
+        drop(relay);
+    }
+
+    #[test]
+    fn test_headless_relay() {
+        let (read, write) = create_pipe().unwrap();
+        let relay = Relay::headless([HANDLE::default(), *write, HANDLE::default()]).unwrap();
+        let remote = relay.remote_handles();
+        // Only the output we actually have gets relayed.
+        assert!(remote[0].is_invalid());
+        assert_ne!(remote[1], *write);
+        assert!(!remote[1].is_invalid());
+        assert!(remote[2].is_invalid());
+
+        unsafe { WriteFile(remote[1], Some(b"hello"), None, None) }.unwrap();
+        drop(relay);
+        drop(write);
+        let mut buffer = [0u8; 16];
+        let mut count = 0;
+        unsafe { ReadFile(*read, Some(&mut buffer), Some(&mut count), None) }.unwrap();
+        assert_eq!(&buffer[..count as usize], b"hello");
+    }
+}
@@ -0,0 +1,72 @@
+use crate::edit_handler::file_request;
+use crate::helpers::*;
+use crate::iolog::{parse_recording, parse_recording_id, playback_delay, recording_file_name};
+use crate::r;
+use crate::run_handler::ElevatedSession;
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use windows::{core::*, Win32::Foundation::*};
+
+/// Entrypoint for `sudo replay`. Plays back the recording of an elevated
+/// session, `speed` times as fast as it happened.
+///
+/// Only admins can read the recordings, so unless we're elevated already, we
+/// copy it out with `sudo read`, like `sudo edit` does. The playback itself
+/// happens unelevated.
+pub fn replay_recording(id: &str, speed: f64) -> Result<i32> {
+    let Some(event_id) = parse_recording_id(id) else {
+        eprintln!("{}", r::IDS_REPLAY_BADID.get().replace("{0}", id));
+        return Ok(ERROR_INVALID_PARAMETER.0 as i32);
+    };
+    let path = recordings_dir()?.join(recording_file_name(&event_id));
+
+    let recording = match read_recording(&path) {
+        Ok(recording) => recording,
+        Err(err) if err.code() == E_FILENOTFOUND => {
+            eprintln!("{}", r::IDS_REPLAY_NOTFOUND.get().replace("{0}", id));
+            return Ok(ERROR_FILE_NOT_FOUND.0 as i32);
+        }
+        Err(err) => return Err(err),
+    };
+    let Some(events) = parse_recording(&String::from_utf8_lossy(&recording)) else {
+        return Err(ERROR_INVALID_DATA.into());
+    };
+
+    let mut stdout = std::io::stdout().lock();
+    let mut previous = 0.0;
+    for event in events {
+        std::thread::sleep(playback_delay(previous, event.time, speed));
+        previous = event.time;
+        stdout.write_all(event.text.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(0)
+}
+
+fn read_recording(path: &Path) -> Result<Vec<u8>> {
+    if is_running_elevated()? {
+        return Ok(std::fs::read(path)?);
+    }
+
+    let temp_path = env::temp_dir().join(format!("sudo_replay_{}.cast", std::process::id()));
+    let result = read_recording_into(path, &temp_path);
+    _ = std::fs::remove_file(&temp_path);
+    result
+}
+
+fn read_recording_into(path: &Path, temp_path: &Path) -> Result<Vec<u8>> {
+    let temp_file = File::create(temp_path)?;
+    let description = join_args(&["replay".to_string(), path.to_string_lossy().into_owned()]);
+    let session = ElevatedSession::start(&description)?;
+    let code = session.run(&file_request("read", path, 1, &temp_file, GUID::zeroed())?)?;
+    drop(temp_file);
+    match code {
+        0 => Ok(std::fs::read(temp_path)?),
+        // `sudo read` exits with an HRESULT if it fails, or this if there's
+        // no such file.
+        code if code == ERROR_FILE_NOT_FOUND.0 as i32 => Err(E_FILENOTFOUND.into()),
+        code => Err(HRESULT(code).into()),
+    }
+}
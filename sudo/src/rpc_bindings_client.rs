@@ -1,9 +1,11 @@
-use crate::helpers::SudoMode;
+use crate::helpers::{RunAs, SudoMode};
+use crate::messages::{ConsoleState, JobLimits};
 use crate::rpc_bindings::Utf8Str;
 use std::ffi::{c_void, CStr};
 use windows::core::{s, GUID, HRESULT, PCSTR, PSTR};
 use windows::Win32::Foundation::HANDLE;
-use windows::Win32::Storage::FileSystem::{GetFileType, FILE_TYPE_DISK, FILE_TYPE_PIPE};
+use windows::Win32::Storage::FileSystem::{GetFileType, FILE_TYPE, FILE_TYPE_DISK, FILE_TYPE_PIPE};
+use windows::Win32::System::Pipes::GetNamedPipeInfo;
 use windows::Win32::System::Rpc::{
     RpcBindingFree, RpcBindingFromStringBindingA, RpcMgmtIsServerListening,
     RpcStringBindingComposeA, RpcStringFreeA, RPC_STATUS, RPC_S_OK,
@@ -18,12 +20,23 @@ extern "C" {
         pipe_handles: *const [HANDLE; 3], // in, out, err
         file_handles: *const [HANDLE; 3], // in, out, err
         sudo_mode: u32,
+        run_as: u32,
+        flags: u32,
+        timeout_ms: u32,
+        pseudoconsole_size: u32,
         application: Utf8Str,
         args: Utf8Str,
         target_dir: Utf8Str,
         env_vars: Utf8Str,
+        keep_privileges: Utf8Str,
+        drop_privileges: Utf8Str,
+        limits: JobLimits,
+        console: ConsoleState,
+        console_title: Utf8Str,
         event_id: GUID,
+        batch_id: GUID,
         child: *mut HANDLE,
+        job: *mut HANDLE,
     ) -> HRESULT;
 
     fn seh_wrapper_client_Shutdown(binding: *mut c_void) -> HRESULT;
@@ -68,25 +81,57 @@ pub fn rpc_client_cleanup() {
     }
 }
 
+/// Whether we can send `handle` to the elevated sudo as-is. Anything else
+/// (the console, NUL, serial ports, sockets, ...) gets dropped by
+/// rpc_client_do_elevation_request, so it needs relaying through a pipe of its
+/// own instead.
+pub fn can_send_over_rpc(handle: HANDLE) -> bool {
+    marshal_as(handle).is_some()
+}
+
+/// Which kind of system_handle to send `handle` as, if any. GetFileType says
+/// sockets are pipes too, but they can't be marshalled as one, so we check
+/// that it really is a pipe.
+fn marshal_as(handle: HANDLE) -> Option<FILE_TYPE> {
+    match unsafe { GetFileType(handle) } {
+        FILE_TYPE_PIPE if unsafe { GetNamedPipeInfo(handle, None, None, None, None) }.is_ok() => {
+            Some(FILE_TYPE_PIPE)
+        }
+        FILE_TYPE_DISK => Some(FILE_TYPE_DISK),
+        _ => None,
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn rpc_client_do_elevation_request(
     parent_handle: HANDLE,
     handles: &[HANDLE; 3], // in, out, err
     sudo_mode: SudoMode,
+    run_as: RunAs,
+    flags: u32,
+    timeout_ms: u32,
+    pseudoconsole_size: u32,
     application: Utf8Str,
     args: Utf8Str,
     target_dir: Utf8Str,
     env_vars: Utf8Str,
+    keep_privileges: Utf8Str,
+    drop_privileges: Utf8Str,
+    limits: JobLimits,
+    console: ConsoleState,
+    console_title: Utf8Str,
     event_id: GUID,
+    batch_id: GUID,
     child: *mut HANDLE,
+    job: *mut HANDLE,
 ) -> HRESULT {
     let mut pipe_handles = [HANDLE::default(); 3];
     let mut file_handles = [HANDLE::default(); 3];
 
     for i in 0..3 {
-        match unsafe { GetFileType(handles[i]) } {
-            FILE_TYPE_PIPE => pipe_handles[i] = handles[i],
-            FILE_TYPE_DISK => file_handles[i] = handles[i],
+        match marshal_as(handles[i]) {
+            Some(FILE_TYPE_PIPE) => pipe_handles[i] = handles[i],
+            Some(FILE_TYPE_DISK) => file_handles[i] = handles[i],
             _ => {}
         }
     }
@@ -98,12 +143,23 @@ pub fn rpc_client_do_elevation_request(
             &pipe_handles,
             &file_handles,
             sudo_mode.into(),
+            run_as.into(),
+            flags,
+            timeout_ms,
+            pseudoconsole_size,
             application,
             args,
             target_dir,
             env_vars,
+            keep_privileges,
+            drop_privileges,
+            limits,
+            console,
+            console_title,
             event_id,
+            batch_id,
             child,
+            job,
         )
     }
 }
@@ -1,12 +1,20 @@
 use crate::helpers::*;
 use crate::{
-    elevate_handler::handle_elevation_request, messages::ElevateRequest, rpc_bindings::Utf8Str,
+    elevate_handler::{handle_elevation_request, has_watchers},
+    messages::{
+        ConsoleState, ElevateRequest, JobLimits, REQUEST_FLAG_DETACHED, REQUEST_FLAG_HEADLESS,
+        REQUEST_FLAG_KILL_ON_EXIT,
+    },
+    rpc_bindings::Utf8Str,
+    tracing,
 };
 use std::ffi::{c_void, CStr};
 use std::mem::{size_of, take};
 use std::ptr::null_mut;
 use std::sync::atomic::{AtomicBool, Ordering};
-use windows::Win32::Foundation::{ERROR_BUSY, GENERIC_ALL, HANDLE, PSID};
+use std::sync::OnceLock;
+use std::time::Duration;
+use windows::Win32::Foundation::{ERROR_BUSY, E_ACCESSDENIED, GENERIC_ALL, HANDLE, PSID};
 use windows::{
     core::*, Win32::Security::Authorization::*, Win32::Security::*, Win32::System::Memory::*,
     Win32::System::Rpc::*, Win32::System::SystemServices::*, Win32::System::Threading::*,
@@ -16,12 +24,16 @@ extern "C" {
     static mut server_sudo_rpc_ServerIfHandle: *mut c_void;
 }
 
-static mut EXPECTED_CLIENT_PID: u32 = 0;
+static EXPECTED_CLIENT: OnceLock<ExpectedClient> = OnceLock::new();
 
 // Process-wide mutex to ensure that only one request is handled at a time. The
 // bool inside the atomic is true if we've already started handling a request.
 static RPC_SERVER_IN_USE: AtomicBool = AtomicBool::new(false);
 
+// True if we were started for a `--batch`. In that case, our client will send
+// us one request per command, and we keep listening until it calls Shutdown.
+static BATCH_MODE: AtomicBool = AtomicBool::new(false);
+
 // * Context: The callback function may pass this handle to
 //   RpcImpersonateClient, RpcBindingServerFromClient,
 //   RpcGetAuthorizationContextForClient, or any other server side function that
@@ -43,15 +55,20 @@ unsafe extern "system" fn rpc_server_callback(
         return status;
     }
 
+    let Some(expected) = EXPECTED_CLIENT.get() else {
+        return RPC_S_ACCESS_DENIED;
+    };
+
     // Check #1: We'll check that the client process is the one we expected,
     // when we were first started.
     let client_pid = GetProcessId(*client_handle); // if this fails, it returns 0
-    if client_pid == 0 || client_pid != EXPECTED_CLIENT_PID {
+    if client_pid == 0 || client_pid != expected.pid {
         return RPC_S_ACCESS_DENIED;
     }
 
-    // Check #2: Check that the client process is the same as the server process.
-    if check_client(*client_handle).is_err() {
+    // Check #2: Check that the client process is the same as the server
+    // process, and that it's the same process (and user) that started us.
+    if check_client(*client_handle, expected).is_err() {
         return RPC_S_ACCESS_DENIED;
     }
 
@@ -134,8 +151,12 @@ fn create_security_descriptor_for_process(pid: u32) -> Result<OwnedSecurityDescr
     }
 }
 
-pub fn rpc_server_setup(endpoint: &CStr, expected_client_pid: u32) -> Result<()> {
-    let owned_sd = create_security_descriptor_for_process(expected_client_pid)?;
+pub fn rpc_server_setup(
+    endpoint: &CStr,
+    expected_client: ExpectedClient,
+    batch: bool,
+) -> Result<()> {
+    let owned_sd = create_security_descriptor_for_process(expected_client.pid)?;
 
     unsafe {
         RpcServerUseProtseqEpA(
@@ -157,7 +178,8 @@ pub fn rpc_server_setup(endpoint: &CStr, expected_client_pid: u32) -> Result<()>
         )
         .ok()?;
 
-        EXPECTED_CLIENT_PID = expected_client_pid;
+        _ = EXPECTED_CLIENT.set(expected_client);
+        BATCH_MODE.store(batch, Ordering::Relaxed);
 
         let res = RpcServerListen(
             /* MinimumCallThreads */ 1,
@@ -174,6 +196,15 @@ pub fn rpc_server_setup(endpoint: &CStr, expected_client_pid: u32) -> Result<()>
 // This is the RPC's sudo_rpc::Shutdown callback function.
 #[no_mangle]
 unsafe extern "C" fn server_Shutdown(_binding: *const c_void) {
+    // If we're still watching a client so we can kill its target when it
+    // exits, we can't just die here - that would close the target's job and
+    // kill it now. The same goes for a target's pseudoconsole. Stop the server
+    // instead, and let start_rpc_server wait for the watchers.
+    if has_watchers() {
+        _ = RpcMgmtStopServerListening(None);
+        _ = RpcServerUnregisterIf(None, None, 0);
+        return;
+    }
     _ = TerminateProcess(GetCurrentProcess(), 0);
 }
 
@@ -185,12 +216,23 @@ pub extern "C" fn server_DoElevationRequest(
     pipe_handles: *const [HANDLE; 3], // in, out, err
     file_handles: *const [HANDLE; 3], // in, out, err
     sudo_mode: u32,
+    run_as: u32,
+    flags: u32,
+    timeout_ms: u32,
+    pseudoconsole_size: u32,
     application: Utf8Str,
     args: Utf8Str,
     target_dir: Utf8Str,
     env_vars: Utf8Str,
+    keep_privileges: Utf8Str,
+    drop_privileges: Utf8Str,
+    limits: JobLimits,
+    console: ConsoleState,
+    console_title: Utf8Str,
     event_id: GUID,
+    batch_id: GUID,
     child: *mut HANDLE,
+    job: *mut HANDLE,
 ) -> HRESULT {
     // Only the first caller will get their request handled. Everyone else will
     // be forced to bail out.
@@ -207,9 +249,16 @@ pub extern "C" fn server_DoElevationRequest(
     // Immediately unregister ourself. This will prevent a future caller from
     // getting to us (but won't cancel the current request we're already in the
     // middle of replying to).
-    unsafe {
-        _ = RpcMgmtStopServerListening(None);
-        _ = RpcServerUnregisterIf(None, None, 0);
+    //
+    // In batch mode, our (one) client is going to send us more requests, so
+    // we stay registered until it calls Shutdown. The callback still makes sure
+    // that nobody else can get to us.
+    let batch_mode = BATCH_MODE.load(Ordering::Relaxed);
+    if !batch_mode {
+        unsafe {
+            _ = RpcMgmtStopServerListening(None);
+            _ = RpcServerUnregisterIf(None, None, 0);
+        }
     }
 
     let result = wrap_elevate_request(
@@ -217,34 +266,87 @@ pub extern "C" fn server_DoElevationRequest(
         pipe_handles,
         file_handles,
         sudo_mode,
+        run_as,
+        flags,
+        timeout_ms,
+        pseudoconsole_size,
         application,
         args,
         target_dir,
         env_vars,
+        keep_privileges,
+        drop_privileges,
+        limits,
+        console,
+        console_title,
         event_id,
+        batch_id,
     )
-    .and_then(|req| handle_elevation_request(&req));
+    .and_then(|req| {
+        check_approved(&req)?;
+        handle_elevation_request(&req, parent_handle)
+    });
+
+    if batch_mode {
+        // Let the next command in the batch through.
+        RPC_SERVER_IN_USE.store(false, Ordering::Relaxed);
+    }
 
     match result {
-        Ok(mut handle) => {
-            unsafe { child.write(take(&mut handle)) };
+        Ok(mut target) => {
+            unsafe {
+                child.write(take(&mut target.process));
+                match &mut target.job {
+                    Some(target_job) => job.write(take(target_job)),
+                    None => job.write(HANDLE::default()),
+                }
+            }
             HRESULT::default()
         }
         Err(err) => err.into(),
     }
 }
 
+/// Makes sure that this is the request that was approved in the UAC dialog.
+/// In batch mode, there's no one request that was approved, but the client is
+/// always the admin who approved the batch. See start_rpc_server.
+fn check_approved(request: &ElevateRequest) -> Result<()> {
+    if BATCH_MODE.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let approved = EXPECTED_CLIENT
+        .get()
+        .and_then(|expected| expected.approved.as_ref());
+    match approved {
+        Some(approved) if approved.matches(request)? => Ok(()),
+        _ => {
+            tracing::trace_log_message("request doesn't match the approved one");
+            Err(E_ACCESSDENIED.into())
+        }
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 fn wrap_elevate_request(
     parent_handle: HANDLE,
     pipe_handles: *const [HANDLE; 3], // in, out, err
     file_handles: *const [HANDLE; 3], // in, out, err
     sudo_mode: u32,
+    run_as: u32,
+    flags: u32,
+    timeout_ms: u32,
+    pseudoconsole_size: u32,
     application: Utf8Str,
     args: Utf8Str,
     target_dir: Utf8Str,
     env_vars: Utf8Str,
+    keep_privileges: Utf8Str,
+    drop_privileges: Utf8Str,
+    limits: JobLimits,
+    console: ConsoleState,
+    console_title: Utf8Str,
     event_id: GUID,
+    batch_id: GUID,
 ) -> Result<ElevateRequest> {
     let parent_pid = unsafe { GetProcessId(parent_handle) };
     let handles = unsafe {
@@ -257,11 +359,27 @@ fn wrap_elevate_request(
         parent_pid,
         handles,
         sudo_mode: sudo_mode.try_into()?,
+        run_as: run_as.try_into()?,
+        kill_on_exit: flags & REQUEST_FLAG_KILL_ON_EXIT != 0,
+        timeout: (timeout_ms != 0).then(|| Duration::from_millis(timeout_ms.into())),
+        usage_report: None,
+        wait: false,
+        detached: flags & REQUEST_FLAG_DETACHED != 0,
+        pid_file: None,
+        headless: flags & REQUEST_FLAG_HEADLESS != 0,
+        pseudoconsole: unpack_coord_from_rpc(pseudoconsole_size),
+        relay_output: false,
         application: application.as_str()?.to_owned(),
         args: unpack_string_list_from_rpc(args)?,
         target_dir: target_dir.as_str()?.to_owned(),
         env_vars: env_vars.as_str()?.to_owned(),
+        keep_privileges: unpack_string_list_from_rpc(keep_privileges)?,
+        drop_privileges: unpack_string_list_from_rpc(drop_privileges)?,
+        limits,
+        console,
+        console_title: console_title.as_str()?.to_owned(),
         event_id,
+        batch_id,
     })
 }
 
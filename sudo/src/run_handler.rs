@@ -1,47 +1,35 @@
-use crate::elevate_handler::spawn_target_for_request;
+use crate::elevate_handler::{
+    keep_job_running, make_console_like_clients, spawn_target_for_request, Target,
+};
 use crate::helpers::*;
-use crate::logging_bindings::event_log_request;
-use crate::messages::ElevateRequest;
+use crate::logging_bindings::{event_log_request, event_log_timeout};
+use crate::messages::{ConsoleState, ElevateRequest, JobLimits};
+use crate::relay::{console_size, Relay};
 use crate::rpc_bindings::Utf8Str;
 use crate::rpc_bindings_client::{
     rpc_client_cleanup, rpc_client_do_elevation_request, rpc_client_setup,
 };
+use crate::usage::{Usage, UsageFormat};
 use crate::{r, tracing};
 use std::env;
 use std::ffi::{CString, OsStr};
 use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
 use windows::Wdk::Foundation::{NtQueryObject, ObjectBasicInformation};
 use windows::Win32::System::WindowsProgramming::PUBLIC_OBJECT_BASIC_INFORMATION;
 use windows::{
     core::*, Wdk::System::Threading::*, Win32::Foundation::*, Win32::Storage::FileSystem::*,
-    Win32::System::Console::*, Win32::System::Diagnostics::Debug::*, Win32::System::Rpc::*,
-    Win32::System::SystemInformation::*, Win32::System::Threading::*, Win32::UI::Shell::*,
-    Win32::UI::WindowsAndMessaging::*,
+    Win32::System::Console::*, Win32::System::Diagnostics::Debug::*, Win32::System::JobObjects::*,
+    Win32::System::Rpc::*, Win32::System::SystemInformation::*, Win32::System::Threading::*,
+    Win32::UI::Shell::*, Win32::UI::WindowsAndMessaging::*,
 };
 
-fn current_elevation_matches_request(is_admin: bool, _req: &ElevateRequest) -> bool {
-    // FUTURE TODO: actually support running as another user.
-    is_admin
-}
-
-/// helper to find the process creation time for a given process handle
-/// process_handle: handle to the process to get the creation time for. This is a non-owning handle.
-fn get_process_creation_time(process_handle: HANDLE) -> Result<FILETIME> {
-    unsafe {
-        // You actually have to pass in valid pointers to these, even if we don't need them.
-        let mut creation_time = FILETIME::default();
-        let mut exit_time = FILETIME::default();
-        let mut kernel_time = FILETIME::default();
-        let mut user_time = FILETIME::default();
-        GetProcessTimes(
-            process_handle,
-            &mut creation_time,
-            &mut exit_time,
-            &mut kernel_time,
-            &mut user_time,
-        )?;
-        Ok(creation_time)
-    }
+fn current_elevation_matches_request(is_admin: bool, req: &ElevateRequest) -> bool {
+    // Dropping elevation never needs the elevated sudo. If we're elevated,
+    // spawn_target_for_request will get the unelevated token itself, and if
+    // we're not, we're already running as the right user.
+    is_admin || req.run_as == RunAs::Unelevated
 }
 
 fn is_in_windows_dir(path: &Path) -> bool {
@@ -180,15 +168,280 @@ fn adjust_args_for_gui_exes(req: &mut ElevateRequest) {
     }
 }
 
-pub fn run_target(
-    copy_env: bool,
-    args: &[&String],
-    sudo_mode: SudoMode,
-    requested_dir: Option<String>,
-) -> Result<i32> {
-    let manually_requested_dir = requested_dir.is_some();
-    let req = prepare_request(copy_env, args, sudo_mode, requested_dir)?;
-    do_request(req, copy_env, manually_requested_dir)
+/// Captures how our console is set up: its code pages, whether it has VT
+/// processing on, its size, and its title. A target in a new window gets a
+/// console with the default settings otherwise, which shows mojibake if
+/// they've been working with `chcp 65001`.
+///
+/// Anything we can't get at is left zeroed (or empty, for the title), so if
+/// we don't have a console at all, the state is empty.
+fn current_console_state() -> (ConsoleState, String) {
+    let mut state = ConsoleState::default();
+    unsafe {
+        state.input_cp = GetConsoleCP();
+        state.output_cp = GetConsoleOutputCP();
+
+        // Either of stdout and stderr might be redirected. Use whichever one
+        // is still the console.
+        for idx in [STD_OUTPUT_HANDLE, STD_ERROR_HANDLE] {
+            let Ok(h) = GetStdHandle(idx) else {
+                continue;
+            };
+            let mut mode = CONSOLE_MODE::default();
+            let mut info = CONSOLE_SCREEN_BUFFER_INFO::default();
+            if GetConsoleMode(h, &mut mode).is_err()
+                || GetConsoleScreenBufferInfo(h, &mut info).is_err()
+            {
+                continue;
+            }
+            state.virtual_terminal = (mode & ENABLE_VIRTUAL_TERMINAL_PROCESSING).0;
+            state.window_size = pack_coord_for_rpc(Some(COORD {
+                X: info.srWindow.Right - info.srWindow.Left + 1,
+                Y: info.srWindow.Bottom - info.srWindow.Top + 1,
+            }));
+            state.buffer_size = pack_coord_for_rpc(Some(info.dwSize));
+            break;
+        }
+    }
+
+    let mut title = [0u16; 1024];
+    let len = unsafe { GetConsoleTitleW(&mut title) } as usize;
+    let title = String::from_utf16_lossy(&title[..len.min(title.len())]);
+    (state, title)
+}
+
+/// Builds the args for `wt.exe` to run `application` in a new tab, in
+/// `target_dir`, with the given profile (or the default one, if it's empty).
+///
+/// `-w 0` puts the tab in the most recent Windows Terminal window, or a new
+/// window if there isn't one. Only an elevated window can take it, since wt
+/// runs elevated too. wt treats a `;` on the end of an arg (or on its own) as
+/// the start of another command, so those get escaped as `\;`. Everything
+/// after `--` is the commandline for the tab.
+///
+/// An arg that already ends in `\;` can't get through wt at all, since wt
+/// turns it into `;` either way. Returns None for those, and the command
+/// stays in conhost.
+pub fn build_wt_commandline(
+    profile: &str,
+    target_dir: &str,
+    application: &str,
+    args: &[String],
+) -> Option<Vec<String>> {
+    fn escape(arg: &str) -> Option<String> {
+        if arg.ends_with("\\;") {
+            return None;
+        }
+        Some(match arg.strip_suffix(';') {
+            Some(rest) => format!("{rest}\\;"),
+            None => arg.to_string(),
+        })
+    }
+
+    let mut wt_args: Vec<String> = vec!["-w".into(), "0".into(), "new-tab".into()];
+    if !profile.is_empty() {
+        wt_args.extend(["-p".into(), escape(profile)?]);
+    }
+    wt_args.extend([
+        "-d".into(),
+        escape(target_dir)?,
+        "--".into(),
+        escape(application)?,
+    ]);
+    for arg in args {
+        wt_args.push(escape(arg)?);
+    }
+    Some(wt_args)
+}
+
+/// Checks if a command in a new window can run in Windows Terminal instead.
+/// wt only hands the command off to a window and exits, so we never get the
+/// target's process. Anything that needs it has to stay in conhost. So does
+/// anything that changes how the target starts, which wt wouldn't pass on.
+fn can_use_windows_terminal(options: &RunOptions, req: &ElevateRequest) -> bool {
+    !req.wait
+        && !options.copy_env
+        && req.run_as == RunAs::Default
+        && req.keep_privileges.is_empty()
+        && req.drop_privileges.is_empty()
+        && req.limits.is_empty()
+        && req.timeout.is_none()
+        && req.usage_report.is_none()
+}
+
+/// The options that control how a single command gets run. These come from the
+/// commandline, or from one line of a batch file.
+#[derive(Clone)]
+pub struct RunOptions {
+    pub copy_env: bool,
+    pub sudo_mode: SudoMode,
+    pub requested_dir: Option<String>,
+    pub run_as: RunAs,
+    /// `--privilege`. If this isn't empty, these are the only privileges the
+    /// target gets.
+    pub keep_privileges: Vec<String>,
+    /// `--drop-privilege`.
+    pub drop_privileges: Vec<String>,
+    /// Kill the target if we exit before it does. `--no-kill-on-exit` turns
+    /// this off.
+    pub kill_on_exit: bool,
+    /// `--memory-limit`, `--cpu-rate`, and friends.
+    pub limits: JobLimits,
+    /// `--timeout`.
+    pub timeout: Option<Duration>,
+    /// `--time`.
+    pub usage_report: Option<UsageFormat>,
+    /// `--wait`. Wait for the target to exit, even in new window mode.
+    pub wait: bool,
+    /// `-b`. Start the target detached, and don't wait for it.
+    pub background: bool,
+    /// `--pid-file`.
+    pub pid_file: Option<String>,
+    /// `--pty`. Give the target a pseudoconsole, instead of sharing ours.
+    pub pseudoconsole: bool,
+    /// `--wt`, or the Windows Terminal setting. If this is set, a new window
+    /// is a Windows Terminal tab, with this profile (or the default one, if
+    /// it's empty).
+    pub windows_terminal: Option<String>,
+}
+
+impl RunOptions {
+    /// The options sudo uses to run one of its own subcommands (like `sudo
+    /// write`), where the elevated side needs to use our std handles.
+    pub fn inline() -> Self {
+        RunOptions {
+            copy_env: false,
+            sudo_mode: SudoMode::Normal,
+            requested_dir: None,
+            run_as: RunAs::Default,
+            keep_privileges: Vec::new(),
+            drop_privileges: Vec::new(),
+            kill_on_exit: true,
+            limits: JobLimits::default(),
+            timeout: None,
+            usage_report: None,
+            wait: false,
+            background: false,
+            pid_file: None,
+            pseudoconsole: false,
+            windows_terminal: None,
+        }
+    }
+}
+
+/// One command from a `--batch` file, along with the options it was given.
+pub struct BatchCommand {
+    pub options: RunOptions,
+    pub commandline: Vec<String>,
+}
+
+pub fn run_target(options: &RunOptions, args: &[&String]) -> Result<i32> {
+    let req = prepare_request(options, args, GUID::zeroed())?;
+    do_request(req, options)
+}
+
+/// Runs each of the given commands, in order, under a single elevation. We'll
+/// only start one elevated sudo, and send it one request per command. Each
+/// command gets its own RequestID, but they all share the same BatchID.
+///
+/// Returns the exit code of the first command that failed, or 0 if they all
+/// succeeded. If `stop_on_error` is set, we won't run any more commands after
+/// the first failure.
+pub fn run_batch(commands: &[BatchCommand], batch_name: &str, stop_on_error: bool) -> Result<i32> {
+    let batch_id = GUID::new()?;
+
+    // Prepare all the requests up front. That way, if any of the commands
+    // can't be found, we bail out before running anything.
+    let requests = commands
+        .iter()
+        .map(|command| {
+            let args = command.commandline.iter().collect::<Vec<_>>();
+            prepare_request(&command.options, &args, batch_id)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let session = ElevatedSession::start(batch_name)?;
+
+    let total = requests.len();
+    let mut exit_code = 0;
+    for (index, (command, req)) in commands.iter().zip(&requests).enumerate() {
+        let code = session.run(req).unwrap_or_else(|err| {
+            eprintln!("{} {}", r::IDS_UNKNOWNERROR.get(), err);
+            err.code().0
+        });
+
+        let translated_msg = r::IDS_BATCH_COMMANDRESULT.get();
+        let replaced = translated_msg
+            .replace("{0}", &(index + 1).to_string())
+            .replace("{1}", &total.to_string())
+            .replace("{2}", &join_args(&command.commandline))
+            .replace("{3}", &code.to_string());
+        eprintln!("{}", replaced);
+
+        if code != 0 {
+            if exit_code == 0 {
+                exit_code = code;
+            }
+            if stop_on_error {
+                let remaining = total - index - 1;
+                if remaining > 0 {
+                    let translated_msg = r::IDS_BATCH_STOPPED.get();
+                    eprintln!("{}", translated_msg.replace("{0}", &remaining.to_string()));
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(exit_code)
+}
+
+/// A single elevated sudo that we can send a series of requests to, one at a
+/// time. This is how `--batch` (and `sudo edit`) only need one UAC prompt. If
+/// we're already elevated, we'll just run the requests ourselves.
+pub struct ElevatedSession {
+    is_admin: bool,
+}
+
+impl ElevatedSession {
+    /// Starts the elevated sudo, if we need one. `description` is added to the
+    /// elevated sudo's commandline, so that it shows up in the UAC dialog.
+    pub fn start(description: &str) -> Result<Self> {
+        let is_admin = is_running_elevated()?;
+        if !is_admin {
+            let nonce = start_elevated_server(&join_args(&["--batch", description]))?;
+            connect_to_rpc_server(nonce)?;
+        }
+        Ok(Self { is_admin })
+    }
+
+    /// Runs the given request, waits for it to exit, and returns its exit code.
+    /// Like a single command, a detached target or a new window without
+    /// `--wait` isn't waited for.
+    pub fn run(&self, req: &ElevateRequest) -> Result<i32> {
+        if current_elevation_matches_request(self.is_admin, req) {
+            run_ourselves(req, wait_for_target)
+        } else {
+            let mut relay = start_rpc_relay(req)?;
+            let handles = relay.as_ref().map_or(req.handles, Relay::remote_handles);
+            let target = request_elevation_via_rpc(req, &handles);
+            if let Some(relay) = &mut relay {
+                relay.close_remote_handles();
+            }
+            let result = target.and_then(|target| wait_for_target(req, &target));
+            drop(relay);
+            result
+        }
+    }
+}
+
+impl Drop for ElevatedSession {
+    fn drop(&mut self) {
+        if !self.is_admin {
+            // Clean up (terminate) the RPC server we made.
+            rpc_client_cleanup();
+        }
+    }
 }
 
 /// Constructs an ElevateRequest from the given arguments. We'll package up
@@ -201,11 +454,13 @@ pub fn run_target(
 ///
 /// If the app isn't actually an app, and it's instead a CMD intrinsic, we'll
 /// convert the request to run in CMD (if we were _ourselves_ ran from CMD).
-fn prepare_request(
-    copy_env: bool,
+///
+/// `batch_id` should be zeroed, unless this request is one command out of a
+/// `--batch`.
+pub fn prepare_request(
+    options: &RunOptions,
     args: &[&String],
-    sudo_mode: SudoMode,
-    requested_dir: Option<String>,
+    batch_id: GUID,
 ) -> Result<ElevateRequest> {
     let handle_indices = [STD_INPUT_HANDLE, STD_OUTPUT_HANDLE, STD_ERROR_HANDLE];
 
@@ -230,7 +485,7 @@ fn prepare_request(
 
     // If they passed a directory, use that. Otherwise, "use the current dir"
     // (with the known caveats about new window mode)
-    let actual_dir = match requested_dir {
+    let actual_dir = match &options.requested_dir {
         Some(dir) => {
             // If they passed a directory, we need to canonicalize it. This is
             // because the elevated sudo will start in system32 (because we are
@@ -239,7 +494,7 @@ fn prepare_request(
             //
             // DON'T use std::canonicalize though. That'll give us a UNC path
             // and just about nothing actaully accepts those (CMD.EXE included)
-            absolute_path(Path::new(&dir))?
+            absolute_path(Path::new(dir))?
         }
         None => env::current_dir()?,
     }
@@ -250,12 +505,30 @@ fn prepare_request(
     let mut req = ElevateRequest {
         parent_pid: std::process::id(),
         handles: filtered_handles,
-        sudo_mode,
+        sudo_mode: options.sudo_mode,
+        run_as: options.run_as,
+        kill_on_exit: options.kill_on_exit,
+        timeout: options.timeout,
+        usage_report: options.usage_report,
+        // There's nothing to report until the target exits, so `--time` waits
+        // for it, even in a new window.
+        wait: options.wait || options.usage_report.is_some(),
+        detached: options.background,
+        pid_file: options.pid_file.clone(),
+        headless: false,
+        pseudoconsole: None,
+        relay_output: false,
         application: args[0].clone(),
         args: args.iter().skip(1).map(|arg| arg.to_string()).collect(),
         target_dir: actual_dir,
-        env_vars: copy_env.then(env_as_string).unwrap_or_default(),
+        env_vars: options.copy_env.then(env_as_string).unwrap_or_default(),
+        keep_privileges: options.keep_privileges.clone(),
+        drop_privileges: options.drop_privileges.clone(),
+        limits: options.limits,
+        console: ConsoleState::default(),
+        console_title: String::new(),
         event_id: GUID::new().unwrap(),
+        batch_id,
     };
 
     tracing::trace_run(&req, !is_console[0], !is_console[1]);
@@ -291,21 +564,110 @@ fn prepare_request(
             return Err(E_DIR_BAD_COMMAND_OR_FILE.into());
         }
     }
+
+    // In new window mode (which GUI apps always get), we don't stick around
+    // for the target to exit unless they asked us to, so there's nobody to tie
+    // its lifetime to.
+    // The same goes for a target we've started in the background. That's meant
+    // to outlive us.
+    if (req.sudo_mode == SudoMode::ForceNewWindow && !req.wait) || req.detached {
+        req.kill_on_exit = false;
+    }
+
+    // If we don't have a console at all (we were started by an IDE's task
+    // runner, a scheduled task, or some GUI), there's none for the target to
+    // share. It would fall back to the elevated sudo's, which is hidden.
+    // Instead, we relay its output to wherever ours goes. A new window has a
+    // console of its own, and a detached target doesn't want our output.
+    if req.sudo_mode != SudoMode::ForceNewWindow
+        && !req.detached
+        && unsafe { GetConsoleWindow() }.0 == 0
+    {
+        req.headless = true;
+    }
+
+    // The pseudoconsole stands in for our console, so it's only any use if
+    // the target would have been using our console for everything. In
+    // disabled input mode, it doesn't get to use ours at all.
+    //
+    // If an admin wants elevated sessions recorded, we ask for one even if
+    // they didn't. Otherwise, the target would print straight to our console,
+    // where the elevated sudo can't see it to record it.
+    let config: RegistryConfigProvider = Default::default();
+    let wants_pseudoconsole = options.pseudoconsole
+        || (cfg!(feature = "Feature_conpty") && is_recording_enabled(&config));
+    if wants_pseudoconsole
+        && req.sudo_mode == SudoMode::Normal
+        && !req.detached
+        && is_console.iter().all(|&console| console)
+    {
+        req.pseudoconsole = console_size();
+    }
+
+    // Without one (say, some of its handles are redirected), whatever it
+    // would print to our console comes through us instead, so that it goes
+    // somewhere the elevated sudo can record it.
+    if is_recording_enabled(&config)
+        && req.sudo_mode == SudoMode::Normal
+        && !req.detached
+        && !req.headless
+        && req.pseudoconsole.is_none()
+    {
+        req.relay_output = true;
+    }
+
+    // A new window starts out with the default settings. If the target is a
+    // console app, let the elevated sudo set its console up like ours. GUI
+    // apps don't get a console, and a detached target doesn't either.
+    //
+    // If they'd rather have a Windows Terminal tab, run the target through
+    // wt.exe instead. If there's no wt.exe, they get conhost. This does mean
+    // that the UAC dialog shows Windows Terminal, not the target.
+    //
+    // If an admin wants elevated sessions recorded, a console app can't have
+    // a new window, since nothing it prints there can be recorded. Windows
+    // Terminal doesn't change that, and neither does ShellExecuting it.
+    let is_console_app = get_exe_subsystem(&req.application)
+        .is_ok_and(|subsystem| subsystem == IMAGE_SUBSYSTEM_WINDOWS_CUI);
+    if req.sudo_mode == SudoMode::ForceNewWindow && !req.detached && is_console_app {
+        if is_recording_enabled(&config) {
+            tracing::trace_log_message("recording is on, and a new window can't be recorded");
+            return Err(E_RECORDING_REQUIRED.into());
+        }
+        let windows_terminal = options
+            .windows_terminal
+            .as_ref()
+            .filter(|_| can_use_windows_terminal(options, &req))
+            .and_then(|profile| {
+                let wt_args =
+                    build_wt_commandline(profile, &req.target_dir, &req.application, &req.args)?;
+                Some((which::which("wt.exe").ok()?, wt_args))
+            });
+        if let Some((wt, wt_args)) = windows_terminal {
+            tracing::trace_log_message("Using Windows Terminal for the new window");
+            req.args = wt_args;
+            req.application = wt.to_string_lossy().into_owned();
+        } else {
+            (req.console, req.console_title) = current_console_state();
+        }
+    }
     Ok(req)
 }
 
-fn do_request(req: ElevateRequest, copy_env: bool, manually_requested_dir: bool) -> Result<i32> {
+fn do_request(req: ElevateRequest, options: &RunOptions) -> Result<i32> {
     // Are we already running as admin? If we are, we don't need to do a whole
     // bunch of ShellExecute. We can just spawn the target exe.]
     let is_admin = is_running_elevated()?;
 
     if current_elevation_matches_request(is_admin, &req) {
         // println!("We're already running as admin. Just run the command.");
-        let mut child = spawn_target_for_request(&req)?;
-        match child.wait() {
-            Ok(status) => Ok(status.code().unwrap_or_default()),
-            Err(err) => Err(err.into()),
-        }
+        run_ourselves(&req, |req, target| {
+            if req.detached {
+                report_detached_target(req, target)
+            } else {
+                wait_for_exit(req, target)
+            }
+        })
     } else {
         // We're not running elevated here. We need to start the
         // elevated sudo and send it our request to handle.
@@ -329,13 +691,33 @@ fn do_request(req: ElevateRequest, copy_env: bool, manually_requested_dir: bool)
         //      the Windows dir, but we'd need to recreate the internal logic of
         //      CreateProcess to resolve the commandline we've been given here
         //      to determine that.
-        let should_use_runas =
-            req.sudo_mode == SudoMode::ForceNewWindow && !copy_env && !manually_requested_dir;
+        // * ShellExecuteEx can only start the target as the elevated user,
+        //   with the elevated user's full token. If they want to run as
+        //   someone else (like SYSTEM), or with fewer privileges, we need to
+        //   use RPC.
+        // * ShellExecuteEx gives console apps a new console. If they want it
+        //   started in the background, without one, we need to use RPC.
+        // * That new console has the default settings. If we want it set up
+        //   like ours (see current_console_state), the elevated sudo has to
+        //   make it, so we need to use RPC.
+        let should_use_runas = req.sudo_mode == SudoMode::ForceNewWindow
+            && !options.copy_env
+            && options.requested_dir.is_none()
+            && req.run_as == RunAs::Default
+            && req.keep_privileges.is_empty()
+            && req.drop_privileges.is_empty()
+            && req.limits.is_empty()
+            && req.timeout.is_none()
+            && !req.detached
+            && req.console.is_empty();
 
         if should_use_runas {
             tracing::trace_log_message("Direct ShellExecute");
-            runas_admin(&req.application, &join_args(&req.args), SW_NORMAL)?;
-            Ok(0)
+            let process = runas_admin(&req.application, &join_args(&req.args), SW_NORMAL)?;
+            if !req.wait {
+                return Ok(0);
+            }
+            wait_for_exit(&req, &Target { process, job: None })
         } else {
             tracing::trace_log_message("starting RPC handoff");
             handoff_to_elevated(&req)
@@ -343,6 +725,66 @@ fn do_request(req: ElevateRequest, copy_env: bool, manually_requested_dir: bool)
     }
 }
 
+/// Runs the request ourselves, since we're already elevated enough for it, and
+/// hands the target to `wait`. There's no elevated sudo to keep off of our
+/// console in this case, so there's nothing for a pseudoconsole to protect,
+/// or to record.
+/// We still relay the target's I/O if it gets a hidden console of its own.
+fn run_ourselves<F>(req: &ElevateRequest, wait: F) -> Result<i32>
+where
+    F: FnOnce(&ElevateRequest, &Target) -> Result<i32>,
+{
+    let req = ElevateRequest {
+        pseudoconsole: None,
+        relay_output: false,
+        ..req.clone()
+    };
+    let mut relay = start_relay(&req)?;
+    let req = ElevateRequest {
+        handles: relay.as_ref().map_or(req.handles, Relay::remote_handles),
+        ..req
+    };
+    let target = spawn_in_console_like_ours(&req);
+    if let Some(relay) = &mut relay {
+        relay.close_remote_handles();
+    }
+    let result = target.and_then(|target| wait(&req, &target));
+    drop(relay);
+    result
+}
+
+/// Spawns a target in a new window set up like our console, when we're already
+/// elevated. We can't get at the console CREATE_NEW_CONSOLE would give it, so
+/// like the elevated sudo, we make the window ourselves and let the target
+/// inherit it. Then we go back to our own console, through one of the other
+/// processes still attached to it (like the shell that ran us).
+///
+/// If there's no console to set up, this just spawns the target.
+fn spawn_in_console_like_ours(req: &ElevateRequest) -> Result<Target> {
+    if req.console.is_empty() {
+        return spawn_target_for_request(req);
+    }
+
+    let our_pid = unsafe { GetCurrentProcessId() };
+    let mut pids = [0u32; 64];
+    let count = unsafe { GetConsoleProcessList(&mut pids) } as usize;
+    let console_pid = pids[..count.min(pids.len())]
+        .iter()
+        .copied()
+        .find(|&pid| pid != our_pid);
+
+    make_console_like_clients(&req.console, &req.console_title);
+    let target = spawn_target_for_request(req);
+    unsafe {
+        _ = SetConsoleCtrlHandler(Some(ignore_ctrl_c), false);
+        _ = FreeConsole();
+        if let Some(pid) = console_pid {
+            _ = AttachConsole(pid);
+        }
+    }
+    target
+}
+
 /// Generates a random nonce to include in the RPC endpoint name. We're using
 /// `RtlGenRandom` to generate the number. This is how the core language does it:
 /// https://github.com/rust-lang/rust/pull/45370
@@ -364,7 +806,27 @@ fn random_nonce() -> u32 {
 }
 
 fn handoff_to_elevated(req: &ElevateRequest) -> Result<i32> {
-    // Build a single string from the request's application and args
+    // The elevated sudo only runs the request that gets approved in the UAC
+    // dialog. That's this commandline, and a hash of everything else it needs
+    // to do. The application has to be quoted too, so that the elevated sudo
+    // gets back exactly the args we sent.
+    let mut commandline = vec![
+        "--request".to_string(),
+        req.approval_hash()?,
+        req.application.clone(),
+    ];
+    commandline.extend(req.args.iter().cloned());
+    let nonce = start_elevated_server(&join_args(&commandline))?;
+    send_request_via_rpc(req, nonce)
+}
+
+/// ShellExecutes an elevated copy of ourselves, which will start up an RPC
+/// server for us to send requests to. `commandline` is tacked onto the end of
+/// the elevated sudo's commandline, so that it shows up in the UAC dialog.
+///
+/// Returns the nonce that the elevated sudo will include in its RPC endpoint
+/// name.
+fn start_elevated_server(commandline: &str) -> Result<u32> {
     let parent_pid = std::process::id();
 
     // generate a pseudorandom nonce to include
@@ -376,13 +838,10 @@ fn handoff_to_elevated(req: &ElevateRequest) -> Result<i32> {
     ));
 
     let path = env::current_exe().unwrap();
-    let target_args = format!(
-        "elevate -p {parent_pid} -n {nonce} {} {}",
-        req.application,
-        join_args(&req.args)
-    );
+    let target_args = format!("elevate -p {parent_pid} -n {nonce} {commandline}");
     tracing::trace_log_message(&format!("elevate request: '{target_args:?}'"));
-    runas_admin(&path, &target_args, SW_HIDE)?;
+    // We don't need the elevated sudo's handle. We'll hear from it over RPC.
+    _ = runas_admin(&path, &target_args, SW_HIDE)?;
 
     // Subtle: Add our own CtrlC handler, so that we can ignore it.
     // Otherwise, the console gets into a weird state, where we return
@@ -392,7 +851,7 @@ fn handoff_to_elevated(req: &ElevateRequest) -> Result<i32> {
         _ = SetConsoleCtrlHandler(Some(ignore_ctrl_c), true);
     }
 
-    send_request_via_rpc(req, nonce)
+    Ok(nonce)
 }
 
 /// Connects to the elevated sudo instance via RPC, then makes a couple RPC
@@ -410,6 +869,60 @@ fn handoff_to_elevated(req: &ElevateRequest) -> Result<i32> {
 ///   - Specifically be on the lookout for 1764 here, which is
 ///     RPC_S_CANNOT_SUPPORT
 fn send_request_via_rpc(req: &ElevateRequest, nonce: u32) -> Result<i32> {
+    connect_to_rpc_server(nonce)?;
+
+    // If we're relaying the target's I/O, we send the elevated sudo the pipes
+    // for it, instead of our std handles.
+    let mut relay = start_rpc_relay(req)?;
+    let handles = relay.as_ref().map_or(req.handles, Relay::remote_handles);
+
+    let target = request_elevation_via_rpc(req, &handles);
+    if let Some(relay) = &mut relay {
+        relay.close_remote_handles();
+    }
+
+    // Clean up (terminate) the RPC server we made.
+    rpc_client_cleanup();
+
+    let result = wait_for_target(req, &target?);
+    // Wait for the last of the target's output, before whoever ran us gets
+    // their console back.
+    drop(relay);
+    result
+}
+
+/// Starts relaying the target's I/O, if it won't be using our console itself:
+/// either it gets a pseudoconsole, or it gets a hidden console of its own,
+/// because it's in disabled input mode, or we don't have one. If it's being
+/// recorded, its output doesn't get to use our console either.
+fn start_relay(req: &ElevateRequest) -> Result<Option<Relay>> {
+    if req.pseudoconsole.is_some() {
+        Relay::pseudoconsole().map(Some)
+    } else if req.headless {
+        Relay::headless(req.handles).map(Some)
+    } else if (req.sudo_mode == SudoMode::DisableInput || req.relay_output) && !req.detached {
+        Relay::output_only(req.handles).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// start_relay, for a target that the elevated sudo is starting for us.
+/// Whatever can't be sent over RPC (like NUL, or a socket) gets a pipe of its
+/// own too, as long as we're sticking around to relay it.
+fn start_rpc_relay(req: &ElevateRequest) -> Result<Option<Relay>> {
+    let mut relay = start_relay(req)?;
+    if waits_for_target(req) {
+        relay
+            .get_or_insert_with(|| Relay::passthrough(req.handles))
+            .relay_unsendable_handles()?;
+    }
+    Ok(relay)
+}
+
+/// Connects to the RPC server that the elevated sudo started for us, retrying
+/// for a bit while it starts up.
+fn connect_to_rpc_server(nonce: u32) -> Result<()> {
     let endpoint = generate_rpc_endpoint_name(unsafe { GetCurrentProcessId() }, nonce);
     let endpoint = CString::new(endpoint).unwrap();
 
@@ -436,7 +949,14 @@ fn send_request_via_rpc(req: &ElevateRequest, nonce: u32) -> Result<i32> {
             _ => std::process::exit(connect_result.0),
         }
     }
+    Ok(())
+}
 
+/// Sends a single request to the elevated sudo we're connected to. On success,
+/// returns the target process that it started for us, along with its job (if
+/// it's in one). We can only query the job, unless the request has a timeout.
+/// Then we can kill it too.
+fn request_elevation_via_rpc(req: &ElevateRequest, handles: &[HANDLE; 3]) -> Result<Target> {
     // The GetCurrentProcess() is not a "real" handle and unsuitable to be used with COM.
     // -> We need to clone it first.
     let h_real = unsafe {
@@ -454,25 +974,34 @@ fn send_request_via_rpc(req: &ElevateRequest, nonce: u32) -> Result<i32> {
         process
     };
 
-    tracing::trace_log_message(&format!("sending i/o/e handles: {:?}", req.handles));
+    tracing::trace_log_message(&format!("sending i/o/e handles: {:?}", handles));
 
     let mut child_handle = Owned::default();
+    let mut job_handle: Owned<HANDLE> = Owned::default();
     let rpc_elevate = rpc_client_do_elevation_request(
         *h_real,
-        &req.handles,
+        handles,
         req.sudo_mode,
+        req.run_as,
+        req.flags(),
+        req.timeout_ms(),
+        pack_coord_for_rpc(req.pseudoconsole),
         Utf8Str::new(&req.application),
         Utf8Str::new(&pack_string_list_for_rpc(&req.args)),
         Utf8Str::new(&req.target_dir),
         Utf8Str::new(&req.env_vars),
+        Utf8Str::new(&pack_string_list_for_rpc(&req.keep_privileges)),
+        Utf8Str::new(&pack_string_list_for_rpc(&req.drop_privileges)),
+        req.limits,
+        req.console,
+        Utf8Str::new(&req.console_title),
         req.event_id,
+        req.batch_id,
         &mut *child_handle,
+        &mut *job_handle,
     );
 
     tracing::trace_log_message(&format!("RequestElevation result {rpc_elevate:?}"));
-    // Clean up (terminate) the RPC server we made.
-    rpc_client_cleanup();
-
     rpc_elevate.ok()?;
 
     // Assert that handle_elevation_request() properly limited the handle access rights to just the bits that we needed.
@@ -494,6 +1023,19 @@ fn send_request_via_rpc(req: &ElevateRequest, nonce: u32) -> Result<i32> {
         }
     }
 
+    Ok(Target {
+        process: child_handle,
+        job: (!job_handle.is_invalid()).then_some(job_handle),
+    })
+}
+
+/// Waits for the target process the elevated sudo started for us to exit, and
+/// returns its exit code.
+fn wait_for_target(req: &ElevateRequest, target: &Target) -> Result<i32> {
+    if req.detached {
+        return report_detached_target(req, target);
+    }
+
     // If we were in new window mode, and we're here, then we're
     // ShellExecuting sudo.exe, and then using the elevated sudo to create a
     // new console window. In that case, we want to print an error message
@@ -503,18 +1045,202 @@ fn send_request_via_rpc(req: &ElevateRequest, nonce: u32) -> Result<i32> {
         let translated_msg = r::IDS_LAUNCHEDNEWWINDOW.get();
         let replaced = translated_msg.replace("{0}", &req.application);
         println!("{}", replaced);
-        Ok(0)
+        if !req.wait {
+            return Ok(0);
+        }
+    }
+    wait_for_exit(req, target)
+}
+
+/// Whether we'll wait for the target to exit, after the elevated sudo starts
+/// it. We don't for detached targets, or in new window mode without `--wait`.
+fn waits_for_target(req: &ElevateRequest) -> bool {
+    !req.detached && (req.sudo_mode != SudoMode::ForceNewWindow || req.wait)
+}
+
+/// Tells the caller which process we started in the background, either by
+/// printing its PID, or by writing it to the `--pid-file`. We don't wait for
+/// it, so there's no exit code to return.
+fn report_detached_target(req: &ElevateRequest, target: &Target) -> Result<i32> {
+    let pid = unsafe { GetProcessId(*target.process) };
+    if pid == 0 {
+        return Err(Error::from_win32());
+    }
+    match &req.pid_file {
+        Some(path) => std::fs::write(path, format!("{pid}\n"))?,
+        None => println!("{pid}"),
+    }
+    Ok(0)
+}
+
+/// How Ctrl+C and Ctrl+Break get to the target while we're waiting for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum CtrlForwarding {
+    /// The target is on our console, in our process group, so the console
+    /// sends it Ctrl+C and Ctrl+Break itself. We just need to not die from
+    /// them, and let the target decide what to do.
+    Console,
+    /// The target is on our console, but in its own process group (see
+    /// `--timeout`). The console still sends it Ctrl+Break, but never Ctrl+C,
+    /// so we send it a Ctrl+Break for that instead.
+    ProcessGroup,
+    /// The target is in a hidden console of its own (in disabled input mode,
+    /// or headless), so Ctrl+C here can never reach it. We stop it instead, by
+    /// terminating its job. If it doesn't have one (they passed
+    /// `--no-kill-on-exit`), we just exit, and leave it be.
+    Stop,
+    /// The target has its own console (or pseudoconsole), so that's where its
+    /// Ctrl+C comes from, not here. Or we don't have a console at all.
+    None,
+}
+
+/// Works out how the target gets Ctrl+C and Ctrl+Break. Redirecting the std
+/// handles doesn't matter here - the target is still attached to our console,
+/// and that's where Ctrl+C comes from.
+fn ctrl_forwarding(req: &ElevateRequest, have_console: bool) -> CtrlForwarding {
+    if req.pseudoconsole.is_some() {
+        CtrlForwarding::None
+    } else if req.sudo_mode == SudoMode::DisableInput || req.headless {
+        CtrlForwarding::Stop
+    } else if req.sudo_mode != SudoMode::Normal || !have_console {
+        CtrlForwarding::None
+    } else if req.timeout.is_some() {
+        CtrlForwarding::ProcessGroup
     } else {
+        CtrlForwarding::Console
+    }
+}
+
+// The target that forward_ctrl forwards to (its PID, and its job, if it has
+// one), and how.
+static CTRL_TARGET: Mutex<Option<(u32, Option<HANDLE>, CtrlForwarding)>> = Mutex::new(None);
+
+unsafe extern "system" fn forward_ctrl(ctrl_type: u32) -> BOOL {
+    let Some((pid, job, forwarding)) = *CTRL_TARGET.lock().unwrap() else {
+        return FALSE;
+    };
+    match (forwarding, ctrl_type) {
+        (CtrlForwarding::Stop, CTRL_C_EVENT | CTRL_BREAK_EVENT) => {
+            // The target exits like it got the Ctrl+C itself, and we pass
+            // that on once we see it's gone.
+            let exit_code = STATUS_CONTROL_C_EXIT.0 as u32;
+            match job {
+                Some(job) if TerminateJobObject(job, exit_code).is_ok() => TRUE,
+                _ => ExitProcess(exit_code),
+            }
+        }
+        (CtrlForwarding::ProcessGroup, CTRL_C_EVENT) => {
+            // The target's PID is also its process group's ID. We're not in
+            // that group, so this doesn't come back to us.
+            _ = GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, pid);
+            TRUE
+        }
+        (
+            CtrlForwarding::Console | CtrlForwarding::ProcessGroup,
+            CTRL_C_EVENT | CTRL_BREAK_EVENT,
+        ) => TRUE,
+        _ => FALSE,
+    }
+}
+
+/// Forwards Ctrl+C and Ctrl+Break to the target, for as long as it's around.
+struct CtrlForwarder;
+
+impl CtrlForwarder {
+    fn new(req: &ElevateRequest, target: &Target) -> Self {
+        let have_console = unsafe { GetConsoleWindow() }.0 != 0;
+        let forwarding = ctrl_forwarding(req, have_console);
+        let pid = unsafe { GetProcessId(*target.process) };
+        let job = target.job.as_ref().map(|job| **job);
+        *CTRL_TARGET.lock().unwrap() = Some((pid, job, forwarding));
+        unsafe {
+            _ = SetConsoleCtrlHandler(Some(forward_ctrl), true);
+        }
+        CtrlForwarder
+    }
+}
+
+impl Drop for CtrlForwarder {
+    fn drop(&mut self) {
         unsafe {
+            _ = SetConsoleCtrlHandler(Some(forward_ctrl), false);
+        }
+        *CTRL_TARGET.lock().unwrap() = None;
+    }
+}
+
+/// Waits for the target to exit, and returns its exit code. If it runs past
+/// its timeout, it gets stopped, and we return TIMEOUT_EXIT_CODE instead.
+///
+/// If they asked for `--time`, this prints the report once the target exits.
+fn wait_for_exit(req: &ElevateRequest, target: &Target) -> Result<i32> {
+    let timeout = match req.timeout {
+        Some(_) => req.timeout_ms(),
+        None => INFINITE,
+    };
+    let forwarder = CtrlForwarder::new(req, target);
+    let exit_code = unsafe {
+        if WaitForSingleObject(*target.process, timeout) == WAIT_TIMEOUT {
+            stop_timed_out_target(req, target)
+        } else {
+            // If we started the target ourselves, we have the only handle to
+            // its job. It exited normally, so closing that shouldn't take
+            // anything it left running along with it.
+            if let Some(job) = &target.job {
+                _ = keep_job_running(**job);
+            }
             let mut status = 0u32;
-            _ = WaitForSingleObject(*child_handle, INFINITE);
-            GetExitCodeProcess(*child_handle, &mut status)?;
-            Ok(status as _)
+            GetExitCodeProcess(*target.process, &mut status)?;
+            status as i32
+        }
+    };
+    drop(forwarder);
+
+    if let Some(format) = req.usage_report {
+        // The report goes to stderr, so it doesn't get mixed in with the
+        // target's output if that's being piped somewhere. If we can't make
+        // one, we still have the target's exit code to pass on.
+        match Usage::query(target, exit_code) {
+            Ok(usage) => eprintln!("{}", usage.format(format)),
+            Err(err) => eprintln!(
+                "{}",
+                r::IDS_USAGEFAILED
+                    .get()
+                    .replace("{0}", &req.application)
+                    .replace("{1}", &err.message())
+            ),
         }
     }
+    Ok(exit_code)
 }
 
-fn runas_admin<Exe, Args>(exe: &Exe, args: &Args, show: SHOW_WINDOW_CMD) -> Result<()>
+/// Stops a target that ran past its timeout. First, it gets a Ctrl+Break, so
+/// it has a chance to clean up after itself. Whatever's still running in its
+/// job after the grace period gets killed.
+fn stop_timed_out_target(req: &ElevateRequest, target: &Target) -> i32 {
+    tracing::trace_log_message("target timed out, sending Ctrl+Break");
+    let graceful = unsafe {
+        // The target is in its own process group, so this only goes to it
+        // (and its children), not us or our shell. We can't send anything to
+        // a pseudoconsole from here, though.
+        req.pseudoconsole.is_none()
+            && GenerateConsoleCtrlEvent(CTRL_BREAK_EVENT, GetProcessId(*target.process)).is_ok()
+            && WaitForSingleObject(*target.process, TIMEOUT_GRACE_PERIOD.as_millis() as u32)
+                == WAIT_OBJECT_0
+    };
+    if let Some(job) = &target.job {
+        _ = unsafe { TerminateJobObject(**job, TIMEOUT_EXIT_CODE as u32) };
+    }
+
+    event_log_timeout(req, graceful);
+    let translated_msg = r::IDS_TIMEDOUT.get();
+    eprintln!("{}", translated_msg.replace("{0}", &req.application));
+    TIMEOUT_EXIT_CODE
+}
+
+/// ShellExecutes `exe` elevated, and returns a handle to the process it
+/// started.
+fn runas_admin<Exe, Args>(exe: &Exe, args: &Args, show: SHOW_WINDOW_CMD) -> Result<Owned<HANDLE>>
 where
     Exe: AsRef<OsStr> + ?Sized,
     Args: AsRef<OsStr> + ?Sized,
@@ -522,7 +1248,7 @@ where
     runas_admin_impl(exe.as_ref(), args.as_ref(), show)
 }
 
-fn runas_admin_impl(exe: &OsStr, args: &OsStr, show: SHOW_WINDOW_CMD) -> Result<()> {
+fn runas_admin_impl(exe: &OsStr, args: &OsStr, show: SHOW_WINDOW_CMD) -> Result<Owned<HANDLE>> {
     let cwd = env::current_dir()?;
     let h_exe = HSTRING::from(exe);
     let h_commandline = HSTRING::from(args);
@@ -537,36 +1263,251 @@ fn runas_admin_impl(exe: &OsStr, args: &OsStr, show: SHOW_WINDOW_CMD) -> Result<
         nShow: show.0,
         ..Default::default()
     };
-    unsafe { ShellExecuteExW(&mut sei) }
+    unsafe {
+        ShellExecuteExW(&mut sei)?;
+        Ok(Owned::new(sei.hProcess))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::elevate_handler::is_recordable;
+    use std::os::windows::io::AsRawHandle;
+    use windows::Win32::System::Pipes::CreatePipe;
+
+    fn options(sudo_mode: SudoMode) -> RunOptions {
+        RunOptions {
+            sudo_mode,
+            ..RunOptions::inline()
+        }
+    }
 
     #[test]
     fn test_cmd_is_cui() {
         let app_name = "cmd".to_string();
-        let req = prepare_request(false, &[&app_name], SudoMode::Normal, None).unwrap();
+        let req =
+            prepare_request(&options(SudoMode::Normal), &[&app_name], GUID::zeroed()).unwrap();
         assert_eq!(req.sudo_mode, SudoMode::Normal);
+        // It's using our console, so it doesn't need to know how it's set up.
+        assert!(req.console.is_empty());
     }
     #[test]
     fn test_notepad_is_gui() {
-        let req =
-            prepare_request(false, &[&("notepad".to_string())], SudoMode::Normal, None).unwrap();
+        let req = prepare_request(
+            &options(SudoMode::Normal),
+            &[&("notepad".to_string())],
+            GUID::zeroed(),
+        )
+        .unwrap();
         // If we did in fact find notepad, then we should have set the mode to
         // ForceNewWindow, since it's a GUI app.
         assert_eq!(req.sudo_mode, SudoMode::ForceNewWindow);
+        // It doesn't get a console at all, so there's none to set up.
+        assert!(req.console.is_empty());
 
         // I found in the past that `notepad.exe` worked, while `notepad`
         // didn't. Just make sure they both do, for sanity's sake.
         let req_exe = prepare_request(
-            false,
+            &options(SudoMode::Normal),
             &[&("notepad.exe".to_string())],
-            SudoMode::Normal,
-            None,
+            GUID::zeroed(),
         )
         .unwrap();
         assert_eq!(req_exe.sudo_mode, SudoMode::ForceNewWindow);
     }
+
+    #[test]
+    fn test_build_wt_commandline() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            build_wt_commandline("", r"C:\src", "cmd.exe", &args(&["/k", "dir"])),
+            Some(args(&[
+                "-w", "0", "new-tab", "-d", r"C:\src", "--", "cmd.exe", "/k", "dir"
+            ]))
+        );
+        // The profile can have spaces in it. join_args takes care of quoting
+        // it, along with anything else that needs it.
+        assert_eq!(
+            build_wt_commandline("Admin PowerShell", r"C:\", "pwsh.exe", &[]),
+            Some(args(&[
+                "-w",
+                "0",
+                "new-tab",
+                "-p",
+                "Admin PowerShell",
+                "-d",
+                r"C:\",
+                "--",
+                "pwsh.exe"
+            ]))
+        );
+        // Semicolons would start another wt command. Only the ones on the end
+        // of an arg count.
+        assert_eq!(
+            build_wt_commandline(
+                "",
+                r"C:\",
+                "cmd.exe",
+                &args(&["/c", "echo", "a;b", "c;", ";"])
+            ),
+            Some(args(&[
+                "-w", "0", "new-tab", "-d", r"C:\", "--", "cmd.exe", "/c", "echo", "a;b", r"c\;",
+                r"\;"
+            ]))
+        );
+        // wt would turn one that's already escaped into a plain `;`, and
+        // there's no escaping that. Those have to stay out of wt.
+        assert_eq!(
+            build_wt_commandline("", r"C:\", "cmd.exe", &args(&["/c", "echo", r"d\;"])),
+            None
+        );
+        assert_eq!(build_wt_commandline(r"x\;", r"C:\", "cmd.exe", &[]), None);
+        // Args that look like wt's own are the target's, after the `--`.
+        assert_eq!(
+            build_wt_commandline("", r"C:\", "tool.exe", &args(&["-p", "x"])),
+            Some(args(&[
+                "-w", "0", "new-tab", "-d", r"C:\", "--", "tool.exe", "-p", "x"
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_ctrl_forwarding() {
+        let app_name = "cmd".to_string();
+        let mut req =
+            prepare_request(&options(SudoMode::Normal), &[&app_name], GUID::zeroed()).unwrap();
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::Console);
+        // Without a console, there's nowhere for a Ctrl+C to come from.
+        assert_eq!(ctrl_forwarding(&req, false), CtrlForwarding::None);
+
+        req.timeout = Some(Duration::from_secs(30));
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::ProcessGroup);
+
+        req.sudo_mode = SudoMode::ForceNewWindow;
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::None);
+
+        req.sudo_mode = SudoMode::Normal;
+        req.pseudoconsole = Some(COORD { X: 120, Y: 30 });
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::None);
+
+        // A headless target is in a console of its own, which Ctrl+C can't
+        // get to.
+        req.pseudoconsole = None;
+        req.headless = true;
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::Stop);
+    }
+
+    #[test]
+    fn test_ctrl_c_stops_disable_input_target() {
+        let args = ["ping", "-n", "30", "127.0.0.1"].map(String::from);
+        let args: Vec<_> = args.iter().collect();
+        let mut req =
+            prepare_request(&options(SudoMode::DisableInput), &args, GUID::zeroed()).unwrap();
+        assert_eq!(ctrl_forwarding(&req, true), CtrlForwarding::Stop);
+
+        // Like `sudo --disable-input ping ... | more`. The target is in a
+        // hidden console of its own, so Ctrl+C in ours can't get to it.
+        let mut read = HANDLE::default();
+        let mut write = HANDLE::default();
+        unsafe { CreatePipe(&mut read, &mut write, None, 0) }.unwrap();
+        let (_read, write) = unsafe { (Owned::new(read), Owned::new(write)) };
+        req.handles = [HANDLE::default(), *write, *write];
+
+        let target = spawn_target_for_request(&req).unwrap();
+        let forwarder = CtrlForwarder::new(&req, &target);
+        unsafe {
+            // This is what our console would call, if they pressed Ctrl+C.
+            // Actually sending one would hit everything else on our console
+            // too.
+            assert_eq!(forward_ctrl(CTRL_C_EVENT), TRUE);
+            let waited = WaitForSingleObject(*target.process, 10_000);
+            if waited != WAIT_OBJECT_0 {
+                _ = TerminateProcess(*target.process, 1);
+                panic!("Ctrl+C didn't stop the target");
+            }
+            let mut exit_code = 0;
+            GetExitCodeProcess(*target.process, &mut exit_code).unwrap();
+            assert_eq!(exit_code, STATUS_CONTROL_C_EXIT.0 as u32);
+        }
+        drop(forwarder);
+    }
+
+    #[test]
+    fn test_disable_input_has_no_console_input() {
+        let args = ["cmd", "/c", "set /p line=<CONIN$"].map(String::from);
+        let args: Vec<_> = args.iter().collect();
+        let mut req =
+            prepare_request(&options(SudoMode::DisableInput), &args, GUID::zeroed()).unwrap();
+        // We don't care what it prints, just that it can't read anything.
+        req.handles = Default::default();
+
+        let target = spawn_target_for_request(&req).unwrap();
+        unsafe {
+            // Type a line into our console, if we have one. If the target
+            // were on it, that would be all it needs to exit.
+            let console_in = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("CONIN$");
+            if let Ok(console_in) = &console_in {
+                let console_in = HANDLE(console_in.as_raw_handle() as _);
+                let records = "y\r".encode_utf16().flat_map(|ch| {
+                    [true, false].map(|down| INPUT_RECORD {
+                        EventType: KEY_EVENT as u16,
+                        Event: INPUT_RECORD_0 {
+                            KeyEvent: KEY_EVENT_RECORD {
+                                bKeyDown: down.into(),
+                                wRepeatCount: 1,
+                                uChar: KEY_EVENT_RECORD_0 { UnicodeChar: ch },
+                                ..Default::default()
+                            },
+                        },
+                    })
+                });
+                let records: Vec<_> = records.collect();
+                let mut written = 0;
+                WriteConsoleInputW(console_in, &records, &mut written).unwrap();
+            }
+
+            // Its own console is hidden, so nobody can type into that. It
+            // should still be sitting there, waiting for someone to.
+            let waited = WaitForSingleObject(*target.process, 2_000);
+            _ = TerminateProcess(*target.process, 1);
+            if let Ok(console_in) = &console_in {
+                _ = FlushConsoleInputBuffer(HANDLE(console_in.as_raw_handle() as _));
+            }
+            assert_eq!(waited, WAIT_TIMEOUT, "the target read from our console");
+        }
+    }
+
+    #[test]
+    fn test_partly_redirected_request_is_recordable() {
+        let args = ["cmd", "/c", "echo hi"].map(String::from);
+        let args: Vec<_> = args.iter().collect();
+        let mut req = prepare_request(&options(SudoMode::Normal), &args, GUID::zeroed()).unwrap();
+
+        // Like `sudo write`: stdin is a pipe, but stdout and stderr are on our
+        // console. Those would go straight to it, where nobody can record them.
+        let mut read = HANDLE::default();
+        let mut write = HANDLE::default();
+        unsafe { CreatePipe(&mut read, &mut write, None, 0) }.unwrap();
+        let (read, _write) = unsafe { (Owned::new(read), Owned::new(write)) };
+        req.handles = [*read, HANDLE::default(), HANDLE::default()];
+        req.headless = false;
+        req.pseudoconsole = None;
+        assert!(!is_recordable(&req));
+
+        // With recording on, we relay them, so they come through pipes that
+        // the elevated sudo can record.
+        req.relay_output = true;
+        let relay = start_relay(&req).unwrap().unwrap();
+        let relayed = ElevateRequest {
+            handles: relay.remote_handles(),
+            ..req.clone()
+        };
+        assert_eq!(relayed.handles[0], *read);
+        assert!(is_recordable(&relayed));
+    }
 }
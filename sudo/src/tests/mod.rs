@@ -20,6 +20,28 @@ mod tests {
         assert_eq!(u32::from(SudoMode::Normal), 3);
     }
 
+    #[test]
+    fn test_try_from_u32_for_run_as() {
+        assert_eq!(RunAs::try_from(0), Ok(RunAs::Default));
+        assert_eq!(RunAs::try_from(1), Ok(RunAs::System));
+        assert_eq!(RunAs::try_from(2), Ok(RunAs::TrustedInstaller));
+        assert_eq!(RunAs::try_from(3), Ok(RunAs::Unelevated));
+        assert_eq!(RunAs::try_from(4), Err(ERROR_INVALID_PARAMETER.into()));
+        assert_eq!(u32::from(RunAs::System), 1);
+        assert_eq!(u32::from(RunAs::TrustedInstaller), 2);
+    }
+
+    #[test]
+    fn test_run_as_policy() {
+        assert_eq!(run_as_policy(RunAs::Default), None);
+        assert_eq!(run_as_policy(RunAs::Unelevated), None);
+        assert_eq!(run_as_policy(RunAs::System), Some(POLICY_ALLOW_SYSTEM));
+        assert_eq!(
+            run_as_policy(RunAs::TrustedInstaller),
+            Some(POLICY_ALLOW_TRUSTED_INSTALLER)
+        );
+    }
+
     #[test]
     fn test_generate_rpc_endpoint_name() {
         assert_eq!(
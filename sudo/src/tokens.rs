@@ -0,0 +1,455 @@
+//! Helpers for getting at the tokens of other identities (like SYSTEM), so that
+//! the elevated sudo can start the target as someone other than itself.
+
+use crate::helpers::{is_token_elevated, E_NO_UNELEVATED_TOKEN};
+use crate::tracing;
+use std::ffi::OsString;
+use std::os::windows::ffi::OsStringExt;
+use windows::{
+    core::*, Win32::Foundation::*, Win32::Security::*, Win32::System::Diagnostics::ToolHelp::*,
+    Win32::System::RemoteDesktop::ProcessIdToSessionId, Win32::System::Services::*,
+    Win32::System::Threading::*,
+};
+
+/// Enables (or disables) the given privileges in a token. Note that a token
+/// can only enable privileges that it already has.
+pub fn set_privileges_enabled(token: HANDLE, names: &[PCWSTR], enable: bool) -> Result<()> {
+    for name in names {
+        unsafe {
+            let mut luid = LUID::default();
+            LookupPrivilegeValueW(None, *name, &mut luid)?;
+
+            let privileges = TOKEN_PRIVILEGES {
+                PrivilegeCount: 1,
+                Privileges: [LUID_AND_ATTRIBUTES {
+                    Luid: luid,
+                    Attributes: if enable {
+                        SE_PRIVILEGE_ENABLED
+                    } else {
+                        TOKEN_PRIVILEGES_ATTRIBUTES(0)
+                    },
+                }],
+            };
+            AdjustTokenPrivileges(token, false, Some(&privileges as *const _), 0, None, None)?;
+            // AdjustTokenPrivileges "succeeds" even if the token didn't have
+            // the privilege at all. The only way to tell is GetLastError.
+            let last_error = GetLastError();
+            if last_error == ERROR_NOT_ALL_ASSIGNED {
+                return Err(last_error.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Enables the given privileges in our own process token. Admins have
+/// privileges like SeDebugPrivilege, but they're disabled by default.
+pub fn enable_privileges(names: &[PCWSTR]) -> Result<()> {
+    let mut token = Owned::default();
+    unsafe {
+        OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY,
+            &mut *token,
+        )?;
+    }
+    set_privileges_enabled(*token, names, true)
+}
+
+/// Finds a process with the given exe name, in the same session as us.
+fn find_process_in_our_session(exe_name: &str) -> Result<u32> {
+    unsafe {
+        let mut our_session = 0u32;
+        ProcessIdToSessionId(GetCurrentProcessId(), &mut our_session)?;
+
+        let snapshot = Owned::new(CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?);
+        let mut entry = PROCESSENTRY32W {
+            dwSize: std::mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        Process32FirstW(*snapshot, &mut entry)?;
+        loop {
+            let len = entry
+                .szExeFile
+                .iter()
+                .position(|c| *c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            let name = OsString::from_wide(&entry.szExeFile[..len]);
+
+            let mut session = 0u32;
+            if name.eq_ignore_ascii_case(exe_name)
+                && ProcessIdToSessionId(entry.th32ProcessID, &mut session).is_ok()
+                && session == our_session
+            {
+                return Ok(entry.th32ProcessID);
+            }
+
+            if Process32NextW(*snapshot, &mut entry).is_err() {
+                return Err(ERROR_NOT_FOUND.into());
+            }
+        }
+    }
+}
+
+/// Makes a copy of the token of the given process. The caller picks whether
+/// they want a primary token (to start a process with), or an impersonation
+/// token (to impersonate on a thread).
+pub fn duplicate_process_token(pid: u32, token_type: TOKEN_TYPE) -> Result<Owned<HANDLE>> {
+    unsafe {
+        let process = Owned::new(OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid)?);
+        let mut token = Owned::default();
+        OpenProcessToken(*process, TOKEN_DUPLICATE | TOKEN_QUERY, &mut *token)?;
+
+        let mut duplicate = Owned::default();
+        DuplicateTokenEx(
+            *token,
+            TOKEN_ALL_ACCESS,
+            None,
+            SecurityImpersonation,
+            token_type,
+            &mut *duplicate,
+        )?;
+        Ok(duplicate)
+    }
+}
+
+/// Gets a copy of the SYSTEM token, by borrowing it from winlogon (which runs
+/// as SYSTEM in every session). This requires SeDebugPrivilege, so it only
+/// works when we're elevated.
+pub fn system_token(token_type: TOKEN_TYPE) -> Result<Owned<HANDLE>> {
+    enable_privileges(&[SE_DEBUG_NAME])?;
+    let winlogon = find_process_in_our_session("winlogon.exe")?;
+    tracing::trace_log_message(&format!("borrowing SYSTEM token from pid {winlogon}"));
+    duplicate_process_token(winlogon, token_type)
+}
+
+/// Impersonates a token on the current thread, until this is dropped.
+pub struct Impersonation;
+
+impl Impersonation {
+    pub fn new(token: HANDLE) -> Result<Self> {
+        unsafe { SetThreadToken(None, token)? };
+        Ok(Self)
+    }
+}
+
+impl Drop for Impersonation {
+    fn drop(&mut self) {
+        unsafe {
+            _ = RevertToSelf();
+        }
+    }
+}
+
+/// Impersonates SYSTEM on the current thread, with the privileges needed to
+/// call CreateProcessAsUserW enabled. An admin doesn't have
+/// SeAssignPrimaryTokenPrivilege, but SYSTEM does.
+pub fn impersonate_system() -> Result<Impersonation> {
+    let token = system_token(TokenImpersonation)?;
+    set_privileges_enabled(
+        *token,
+        &[SE_ASSIGNPRIMARYTOKEN_NAME, SE_INCREASE_QUOTA_NAME],
+        true,
+    )?;
+    Impersonation::new(*token)
+}
+
+/// Closes a service (or service manager) handle when dropped.
+struct ServiceHandle(SC_HANDLE);
+
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        unsafe {
+            _ = CloseServiceHandle(self.0);
+        }
+    }
+}
+
+/// Starts the TrustedInstaller service (it's demand-start, so it usually
+/// isn't running), and returns its PID once it's up.
+fn start_trusted_installer() -> Result<u32> {
+    unsafe {
+        let manager = ServiceHandle(OpenSCManagerW(None, None, SC_MANAGER_CONNECT)?);
+        let service = ServiceHandle(OpenServiceW(
+            manager.0,
+            w!("TrustedInstaller"),
+            SERVICE_START | SERVICE_QUERY_STATUS,
+        )?);
+
+        match StartServiceW(service.0, None) {
+            Err(err) if err.code() != ERROR_SERVICE_ALREADY_RUNNING.to_hresult() => {
+                return Err(err)
+            }
+            _ => {}
+        }
+
+        // Give it a little while to get going. It normally takes well under a
+        // second.
+        for _ in 0..100 {
+            let mut status = SERVICE_STATUS_PROCESS::default();
+            let mut needed = 0u32;
+            QueryServiceStatusEx(
+                service.0,
+                SC_STATUS_PROCESS_INFO,
+                Some(std::slice::from_raw_parts_mut(
+                    &mut status as *mut _ as *mut u8,
+                    std::mem::size_of_val(&status),
+                )),
+                &mut needed,
+            )?;
+            if status.dwCurrentState == SERVICE_RUNNING && status.dwProcessId != 0 {
+                return Ok(status.dwProcessId);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        Err(ERROR_SERVICE_REQUEST_TIMEOUT.into())
+    }
+}
+
+/// Gets a copy of the TrustedInstaller token, by starting the service and
+/// borrowing the token from its process. Only SYSTEM can open that token, so
+/// this impersonates SYSTEM while it does.
+///
+/// The service runs in session 0. The copy gets moved into our session, so
+/// that whatever runs with it can use our console (and desktop).
+pub fn trusted_installer_token(token_type: TOKEN_TYPE) -> Result<Owned<HANDLE>> {
+    let pid = start_trusted_installer()?;
+    tracing::trace_log_message(&format!("borrowing TrustedInstaller token from pid {pid}"));
+
+    let _impersonation = impersonate_system()?;
+    let token = duplicate_process_token(pid, token_type)?;
+    unsafe {
+        let mut session = 0u32;
+        ProcessIdToSessionId(GetCurrentProcessId(), &mut session)?;
+        SetTokenInformation(
+            *token,
+            TokenSessionId,
+            &session as *const _ as *const _,
+            std::mem::size_of_val(&session) as u32,
+        )?;
+    }
+    Ok(token)
+}
+
+/// Gets a token for the same user, but unelevated, so that we can start
+/// things as the plain user from an elevated sudo.
+///
+/// Normally, that's the (medium integrity) token linked to ours. If there
+/// isn't one (UAC is off, or this is the built-in Administrator), we fall
+/// back to the token of the shell, which is what the user's unelevated apps
+/// would have gotten anyway.
+///
+/// With UAC off, or as the built-in Administrator, the shell is elevated too.
+/// Then there's no unelevated token to be had, and we fail with
+/// E_NO_UNELEVATED_TOKEN, rather than quietly running the target elevated.
+pub fn unelevated_token() -> Result<Owned<HANDLE>> {
+    // We only get a linked token we can actually start a process with if we
+    // have SeTcbPrivilege. Otherwise it's just an identification token.
+    let _impersonation = impersonate_system()?;
+    let token = match linked_token() {
+        Ok(token) => token,
+        Err(err) => {
+            tracing::trace_log_message(&format!(
+                "no linked token ({err:?}), falling back to explorer's token"
+            ));
+            let explorer = find_process_in_our_session("explorer.exe")?;
+            duplicate_process_token(explorer, TokenPrimary)?
+        }
+    };
+    if is_token_elevated(*token)? {
+        tracing::trace_log_message("the unelevated token is elevated too");
+        return Err(E_NO_UNELEVATED_TOKEN.into());
+    }
+    Ok(token)
+}
+
+fn linked_token() -> Result<Owned<HANDLE>> {
+    unsafe {
+        let mut token = Owned::default();
+        OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut *token)?;
+
+        let mut linked = TOKEN_LINKED_TOKEN::default();
+        let mut size = 0u32;
+        GetTokenInformation(
+            *token,
+            TokenLinkedToken,
+            Some(&mut linked as *mut _ as _),
+            std::mem::size_of_val(&linked) as u32,
+            &mut size,
+        )?;
+        Ok(Owned::new(linked.LinkedToken))
+    }
+}
+
+fn lookup_privilege(name: &str) -> Result<LUID> {
+    let mut luid = LUID::default();
+    unsafe { LookupPrivilegeValueW(None, &HSTRING::from(name), &mut luid)? };
+    Ok(luid)
+}
+
+fn privilege_name(luid: LUID) -> Result<String> {
+    unsafe {
+        let mut buffer = [0u16; 256];
+        let mut len = buffer.len() as u32;
+        LookupPrivilegeNameW(None, &luid, PWSTR(buffer.as_mut_ptr()), &mut len)?;
+        Ok(String::from_utf16_lossy(&buffer[..len as usize]))
+    }
+}
+
+/// Gets the privileges that a token has (enabled or not).
+fn token_privileges(token: HANDLE) -> Result<Vec<LUID_AND_ATTRIBUTES>> {
+    unsafe {
+        let mut size = 0u32;
+        _ = GetTokenInformation(token, TokenPrivileges, None, 0, &mut size);
+        // Use u64s, to get the alignment right for TOKEN_PRIVILEGES.
+        let mut buffer = vec![0u64; (size as usize).div_ceil(8)];
+        GetTokenInformation(
+            token,
+            TokenPrivileges,
+            Some(buffer.as_mut_ptr() as _),
+            size,
+            &mut size,
+        )?;
+        let privileges = &*(buffer.as_ptr() as *const TOKEN_PRIVILEGES);
+        Ok(std::slice::from_raw_parts(
+            privileges.Privileges.as_ptr(),
+            privileges.PrivilegeCount as usize,
+        )
+        .to_vec())
+    }
+}
+
+/// Works out which of the `present` privileges to take out of a token. If
+/// `keep` isn't empty, it's an allow-list, and everything else goes. Anything
+/// in `remove` goes either way.
+pub fn privileges_to_delete(present: &[LUID], keep: &[LUID], remove: &[LUID]) -> Vec<LUID> {
+    present
+        .iter()
+        .filter(|luid| (!keep.is_empty() && !keep.contains(luid)) || remove.contains(luid))
+        .copied()
+        .collect()
+}
+
+/// Makes a copy of `token` without the given privileges, for `--privilege`
+/// and `--drop-privilege`. Returns the new token, along with the names of the
+/// privileges that it still has.
+///
+/// When there's an allow-list, the Administrators group becomes deny-only
+/// too. Otherwise, anything the Administrators group has been granted would
+/// still be available, which isn't what someone asking for just
+/// SeBackupPrivilege would expect.
+pub fn restrict_token(
+    token: HANDLE,
+    keep: &[String],
+    remove: &[String],
+) -> Result<(Owned<HANDLE>, Vec<String>)> {
+    let keep = keep
+        .iter()
+        .map(|name| lookup_privilege(name))
+        .collect::<Result<Vec<_>>>()?;
+    let remove = remove
+        .iter()
+        .map(|name| lookup_privilege(name))
+        .collect::<Result<Vec<_>>>()?;
+
+    let present: Vec<_> = token_privileges(token)?
+        .iter()
+        .map(|privilege| privilege.Luid)
+        .collect();
+    let delete: Vec<_> = privileges_to_delete(&present, &keep, &remove)
+        .into_iter()
+        .map(|luid| LUID_AND_ATTRIBUTES {
+            Luid: luid,
+            Attributes: TOKEN_PRIVILEGES_ATTRIBUTES(0),
+        })
+        .collect();
+
+    // windows-rs doesn't have a definition for this macro.
+    const SECURITY_MAX_SID_SIZE: usize = 88;
+    let mut sid_buffer = [0u8; SECURITY_MAX_SID_SIZE];
+    let mut sid_len = sid_buffer.len() as u32;
+    let admins = PSID(&mut sid_buffer as *mut _ as _);
+    unsafe { CreateWellKnownSid(WinBuiltinAdministratorsSid, None, admins, &mut sid_len)? };
+    let disable = [SID_AND_ATTRIBUTES {
+        Sid: admins,
+        Attributes: 0,
+    }];
+
+    let mut restricted = Owned::default();
+    unsafe {
+        CreateRestrictedToken(
+            token,
+            CREATE_RESTRICTED_TOKEN_FLAGS(0),
+            if keep.is_empty() {
+                None
+            } else {
+                Some(&disable[..])
+            },
+            Some(delete.as_slice()),
+            None,
+            &mut *restricted,
+        )?;
+    }
+
+    let remaining = token_privileges(*restricted)?
+        .iter()
+        .map(|privilege| privilege_name(privilege.Luid))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((restricted, remaining))
+}
+
+/// Opens our own token, in a way that we can make a restricted copy of it to
+/// start a process with.
+pub fn current_token_for_restricting() -> Result<Owned<HANDLE>> {
+    let mut token = Owned::default();
+    unsafe {
+        OpenProcessToken(
+            GetCurrentProcess(),
+            TOKEN_DUPLICATE | TOKEN_QUERY | TOKEN_ASSIGN_PRIMARY,
+            &mut *token,
+        )?;
+    }
+    Ok(token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn luids(ids: &[u32]) -> Vec<LUID> {
+        ids.iter()
+            .map(|id| LUID {
+                LowPart: *id,
+                HighPart: 0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_privileges_to_delete() {
+        let present = luids(&[1, 2, 3, 4]);
+
+        // Nothing asked for, nothing deleted.
+        assert_eq!(privileges_to_delete(&present, &[], &[]), luids(&[]));
+        // An allow-list deletes everything else...
+        assert_eq!(
+            privileges_to_delete(&present, &luids(&[2]), &[]),
+            luids(&[1, 3, 4])
+        );
+        // ...and asking for something the token doesn't have doesn't add it.
+        assert_eq!(
+            privileges_to_delete(&present, &luids(&[2, 9]), &[]),
+            luids(&[1, 3, 4])
+        );
+        // Dropping just takes those out.
+        assert_eq!(
+            privileges_to_delete(&present, &[], &luids(&[3, 9])),
+            luids(&[3])
+        );
+        // Dropping wins over keeping.
+        assert_eq!(
+            privileges_to_delete(&present, &luids(&[2, 3]), &luids(&[3])),
+            luids(&[1, 3, 4])
+        );
+    }
+}
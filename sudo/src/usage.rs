@@ -0,0 +1,223 @@
+use crate::elevate_handler::Target;
+use std::ffi::c_void;
+use std::time::Duration;
+use windows::{
+    core::*, Win32::Foundation::*, Win32::System::JobObjects::*, Win32::System::ProcessStatus::*,
+    Win32::System::Threading::*,
+};
+
+/// The ways `--time` can print its report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsageFormat {
+    /// A table for people to read, like time(1).
+    Text,
+    /// One line of JSON, for scripts.
+    Json,
+}
+
+impl UsageFormat {
+    /// The values we accept for `--time=FORMAT`. These need to line up with
+    /// `from_name`.
+    pub const NAMES: [&'static str; 2] = ["text", "json"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(UsageFormat::Text),
+            "json" => Some(UsageFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What the target used while it ran. If the target was in a job, the CPU
+/// time and I/O include everything it started, too.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub wall_time: Duration,
+    pub user_time: Duration,
+    pub kernel_time: Duration,
+    /// The target's peak working set, in bytes. Unlike the CPU time, this is
+    /// just the target itself, not anything it started.
+    pub peak_working_set: u64,
+    pub io: IO_COUNTERS,
+    pub exit_code: i32,
+}
+
+/// FILETIMEs (and job times) are in 100ns intervals.
+fn duration_from_100ns(intervals: u64) -> Duration {
+    Duration::from_nanos(intervals.saturating_mul(100))
+}
+
+fn filetime_to_u64(time: FILETIME) -> u64 {
+    ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64
+}
+
+impl Usage {
+    /// Collects the usage for a target that's already exited.
+    pub fn query(target: &Target, exit_code: i32) -> Result<Usage> {
+        let process = *target.process;
+        let mut usage = Usage {
+            exit_code,
+            ..Default::default()
+        };
+
+        unsafe {
+            let mut creation = FILETIME::default();
+            let mut exit = FILETIME::default();
+            let mut kernel = FILETIME::default();
+            let mut user = FILETIME::default();
+            GetProcessTimes(process, &mut creation, &mut exit, &mut kernel, &mut user)?;
+            usage.wall_time = duration_from_100ns(
+                filetime_to_u64(exit).saturating_sub(filetime_to_u64(creation)),
+            );
+
+            // This only needs PROCESS_QUERY_LIMITED_INFORMATION, which is all
+            // the elevated sudo gives us.
+            let mut memory = PROCESS_MEMORY_COUNTERS::default();
+            GetProcessMemoryInfo(process, &mut memory, std::mem::size_of_val(&memory) as u32)?;
+            usage.peak_working_set = memory.PeakWorkingSetSize as u64;
+
+            match &target.job {
+                Some(job) => {
+                    let mut info = JOBOBJECT_BASIC_AND_IO_ACCOUNTING_INFORMATION::default();
+                    QueryInformationJobObject(
+                        **job,
+                        JobObjectBasicAndIoAccountingInformation,
+                        &mut info as *mut _ as *mut c_void,
+                        std::mem::size_of_val(&info) as u32,
+                        None,
+                    )?;
+                    usage.user_time = duration_from_100ns(info.BasicInfo.TotalUserTime as u64);
+                    usage.kernel_time = duration_from_100ns(info.BasicInfo.TotalKernelTime as u64);
+                    usage.io = info.IoInfo;
+                }
+                None => {
+                    usage.user_time = duration_from_100ns(filetime_to_u64(user));
+                    usage.kernel_time = duration_from_100ns(filetime_to_u64(kernel));
+                    GetProcessIoCounters(process, &mut usage.io)?;
+                }
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Formats the report. Like time(1), this is meant for scripts as much as
+    /// for people, so the labels aren't localized.
+    pub fn format(&self, format: UsageFormat) -> String {
+        match format {
+            UsageFormat::Text => self.format_text(),
+            UsageFormat::Json => self.format_json(),
+        }
+    }
+
+    fn format_text(&self) -> String {
+        let io = &self.io;
+        format!(
+            "real             {:.3}s\n\
+             user             {:.3}s\n\
+             sys              {:.3}s\n\
+             peak working set {} KB\n\
+             reads            {} ({} KB)\n\
+             writes           {} ({} KB)\n\
+             other I/O        {} ({} KB)\n\
+             exit code        {}",
+            self.wall_time.as_secs_f64(),
+            self.user_time.as_secs_f64(),
+            self.kernel_time.as_secs_f64(),
+            self.peak_working_set / 1024,
+            io.ReadOperationCount,
+            io.ReadTransferCount / 1024,
+            io.WriteOperationCount,
+            io.WriteTransferCount / 1024,
+            io.OtherOperationCount,
+            io.OtherTransferCount / 1024,
+            self.exit_code,
+        )
+    }
+
+    fn format_json(&self) -> String {
+        let io = &self.io;
+        format!(
+            "{{\"wall_ms\":{},\"user_ms\":{},\"kernel_ms\":{},\"peak_working_set_bytes\":{},\
+             \"read_operations\":{},\"read_bytes\":{},\
+             \"write_operations\":{},\"write_bytes\":{},\
+             \"other_operations\":{},\"other_bytes\":{},\
+             \"exit_code\":{}}}",
+            self.wall_time.as_millis(),
+            self.user_time.as_millis(),
+            self.kernel_time.as_millis(),
+            self.peak_working_set,
+            io.ReadOperationCount,
+            io.ReadTransferCount,
+            io.WriteOperationCount,
+            io.WriteTransferCount,
+            io.OtherOperationCount,
+            io.OtherTransferCount,
+            self.exit_code,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Usage {
+        Usage {
+            wall_time: Duration::from_millis(12345),
+            user_time: Duration::from_millis(1500),
+            kernel_time: Duration::from_millis(250),
+            peak_working_set: 8 * 1024 * 1024,
+            io: IO_COUNTERS {
+                ReadOperationCount: 10,
+                WriteOperationCount: 20,
+                OtherOperationCount: 30,
+                ReadTransferCount: 4096,
+                WriteTransferCount: 8192,
+                OtherTransferCount: 512,
+            },
+            exit_code: -1,
+        }
+    }
+
+    #[test]
+    fn test_usage_format_names() {
+        for name in UsageFormat::NAMES {
+            assert!(UsageFormat::from_name(name).is_some(), "{name}");
+        }
+        assert_eq!(UsageFormat::from_name("xml"), None);
+    }
+
+    #[test]
+    fn test_duration_from_100ns() {
+        assert_eq!(duration_from_100ns(10_000_000), Duration::from_secs(1));
+        assert_eq!(
+            duration_from_100ns(u64::MAX),
+            Duration::from_nanos(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_format_text() {
+        assert_eq!(
+            sample().format(UsageFormat::Text),
+            "real             12.345s\n\
+             user             1.500s\n\
+             sys              0.250s\n\
+             peak working set 8192 KB\n\
+             reads            10 (4 KB)\n\
+             writes           20 (8 KB)\n\
+             other I/O        30 (0 KB)\n\
+             exit code        -1"
+        );
+    }
+
+    #[test]
+    fn test_format_json() {
+        assert_eq!(
+            sample().format(UsageFormat::Json),
+            r#"{"wall_ms":12345,"user_ms":1500,"kernel_ms":250,"peak_working_set_bytes":8388608,"read_operations":10,"read_bytes":4096,"write_operations":20,"write_bytes":8192,"other_operations":30,"other_bytes":512,"exit_code":-1}"#
+        );
+    }
+}
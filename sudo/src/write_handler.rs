@@ -0,0 +1,268 @@
+use crate::helpers::*;
+use crate::run_handler::{run_target, RunOptions};
+use crate::tracing;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::Path;
+use windows::core::*;
+
+/// The encodings that `sudo write` can write a file in. The input is always
+/// treated as UTF-8 (or just as raw bytes, for `Utf8`).
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum WriteEncoding {
+    /// Write the bytes exactly as we read them.
+    Utf8,
+    /// Same as `Utf8`, but start a new file with a UTF-8 BOM.
+    Utf8Bom,
+    /// Convert the input to UTF-16LE, and start a new file with a BOM.
+    Utf16Le,
+}
+
+impl WriteEncoding {
+    /// The values we accept for `--encoding`. These need to line up with
+    /// `name` and `from_name`.
+    pub const NAMES: [&'static str; 3] = ["utf8", "utf8bom", "utf16le"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "utf8" => Some(WriteEncoding::Utf8),
+            "utf8bom" => Some(WriteEncoding::Utf8Bom),
+            "utf16le" => Some(WriteEncoding::Utf16Le),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WriteEncoding::Utf8 => "utf8",
+            WriteEncoding::Utf8Bom => "utf8bom",
+            WriteEncoding::Utf16Le => "utf16le",
+        }
+    }
+
+    /// The bytes we write at the start of an empty file.
+    fn bom(self) -> &'static [u8] {
+        match self {
+            WriteEncoding::Utf8 => &[],
+            WriteEncoding::Utf8Bom => &[0xEF, 0xBB, 0xBF],
+            WriteEncoding::Utf16Le => &[0xFF, 0xFE],
+        }
+    }
+}
+
+pub struct WriteOptions {
+    pub append: bool,
+    /// Also copy the input to stdout, like `tee`.
+    pub tee: bool,
+    pub encoding: WriteEncoding,
+}
+
+/// Converts the input to the requested encoding as it streams through. The
+/// input can get split in the middle of a UTF-8 sequence, so we hang on to
+/// any incomplete sequence at the end of a chunk until the next one arrives.
+struct StreamEncoder {
+    encoding: WriteEncoding,
+    pending: Vec<u8>,
+}
+
+impl StreamEncoder {
+    fn new(encoding: WriteEncoding) -> Self {
+        Self {
+            encoding,
+            pending: Vec::new(),
+        }
+    }
+
+    fn encode(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        if self.encoding != WriteEncoding::Utf16Le {
+            out.extend_from_slice(chunk);
+            return;
+        }
+
+        self.pending.extend_from_slice(chunk);
+        let mut start = 0;
+        while start < self.pending.len() {
+            let (valid, invalid) = match std::str::from_utf8(&self.pending[start..]) {
+                Ok(s) => (s, None),
+                Err(e) => {
+                    let valid = &self.pending[start..start + e.valid_up_to()];
+                    // This can't fail, we just checked it.
+                    (std::str::from_utf8(valid).unwrap(), Some(e.error_len()))
+                }
+            };
+            out.extend(valid.encode_utf16().flat_map(u16::to_le_bytes));
+            start += valid.len();
+
+            match invalid {
+                None => break,
+                // An invalid sequence. Replace it, and keep going.
+                Some(Some(len)) => {
+                    out.extend_from_slice(&0xFFFDu16.to_le_bytes());
+                    start += len;
+                }
+                // The chunk ended partway through a sequence. Wait for more.
+                Some(None) => break,
+            }
+        }
+        self.pending.drain(..start);
+    }
+
+    /// Flushes anything left over. If there's still a partial sequence
+    /// sitting around, the input was truncated, so replace it.
+    fn finish(&mut self, out: &mut Vec<u8>) {
+        if !self.pending.is_empty() {
+            out.extend_from_slice(&0xFFFDu16.to_le_bytes());
+            self.pending.clear();
+        }
+    }
+}
+
+/// Entrypoint for `sudo write` (and `sudo tee`).
+///
+/// If we're not elevated, this will ask the elevated sudo to run `sudo write`
+/// for us, with our stdin. Otherwise (including when we _are_ that elevated
+/// sudo), this reads stdin and writes it to the file directly.
+pub fn write_target(file: &str, options: &WriteOptions) -> Result<i32> {
+    if is_running_elevated()? {
+        return write_stdin_to_file(Path::new(file), options);
+    }
+
+    // The elevated sudo won't start in our current directory, so resolve the
+    // path here. That also makes the event log a bit more useful.
+    let path = absolute_path(Path::new(file))?
+        .to_string_lossy()
+        .into_owned();
+    let exe = env::current_exe()?.to_string_lossy().into_owned();
+
+    let mut args = vec![
+        exe,
+        if options.tee { "tee" } else { "write" }.to_string(),
+        "--encoding".to_string(),
+        options.encoding.name().to_string(),
+    ];
+    if options.append {
+        args.push("--append".to_string());
+    }
+    // Make sure the file can't be mistaken for an option.
+    args.push("--".to_string());
+    args.push(path);
+
+    // We need the elevated sudo to be reading our stdin, so this only works
+    // inline. The caller already made sure that's allowed.
+    run_target(&RunOptions::inline(), &args.iter().collect::<Vec<_>>())
+}
+
+fn write_stdin_to_file(path: &Path, options: &WriteOptions) -> Result<i32> {
+    tracing::trace_log_message(&format!("writing stdin to {path:?}"));
+
+    // Don't use truncate(true) here. That opens the file with CREATE_ALWAYS,
+    // which fails on hidden and system files. Opening it and then setting the
+    // length to 0 keeps the existing file (and its ACL and attributes) intact.
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .append(options.append)
+        .open(path)?;
+    if !options.append {
+        file.set_len(0)?;
+    }
+
+    let mut out = Vec::new();
+    if file.metadata()?.len() == 0 {
+        out.extend_from_slice(options.encoding.bom());
+    }
+
+    let mut encoder = StreamEncoder::new(options.encoding);
+    let mut stdin = std::io::stdin().lock();
+    let mut stdout = std::io::stdout().lock();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = stdin.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        let chunk = &buffer[..read];
+        if options.tee {
+            stdout.write_all(chunk)?;
+            stdout.flush()?;
+        }
+        encoder.encode(chunk, &mut out);
+        file.write_all(&out)?;
+        out.clear();
+    }
+    encoder.finish(&mut out);
+    file.write_all(&out)?;
+    file.flush()?;
+
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_chunks(encoding: WriteEncoding, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut encoder = StreamEncoder::new(encoding);
+        let mut out = Vec::new();
+        for chunk in chunks {
+            encoder.encode(chunk, &mut out);
+        }
+        encoder.finish(&mut out);
+        out
+    }
+
+    fn utf16le(s: &str) -> Vec<u8> {
+        s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
+
+    #[test]
+    fn test_encoding_names_round_trip() {
+        for name in WriteEncoding::NAMES {
+            assert_eq!(WriteEncoding::from_name(name).unwrap().name(), name);
+        }
+        assert_eq!(WriteEncoding::from_name("utf-7"), None);
+    }
+
+    #[test]
+    fn test_utf8_is_passed_through() {
+        let input: &[u8] = b"127.0.0.1 localhost\r\n\xff";
+        assert_eq!(encode_chunks(WriteEncoding::Utf8, &[input]), input);
+        assert_eq!(encode_chunks(WriteEncoding::Utf8Bom, &[input]), input);
+    }
+
+    #[test]
+    fn test_utf16_conversion() {
+        let text = "h\u{e9}llo \u{1F96A}\r\n";
+        assert_eq!(
+            encode_chunks(WriteEncoding::Utf16Le, &[text.as_bytes()]),
+            utf16le(text)
+        );
+
+        // Split the input at every possible point, including in the middle of
+        // the multi-byte sequences.
+        let bytes = text.as_bytes();
+        for split in 0..bytes.len() {
+            let (a, b) = bytes.split_at(split);
+            assert_eq!(
+                encode_chunks(WriteEncoding::Utf16Le, &[a, b]),
+                utf16le(text),
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_utf16_invalid_input() {
+        // An invalid byte in the middle gets replaced...
+        assert_eq!(
+            encode_chunks(WriteEncoding::Utf16Le, &[b"a\xffb"]),
+            utf16le("a\u{FFFD}b")
+        );
+        // ...and so does a sequence that never got finished.
+        assert_eq!(
+            encode_chunks(WriteEncoding::Utf16Le, &[b"a\xe2\x82"]),
+            utf16le("a\u{FFFD}")
+        );
+    }
+}